@@ -2,76 +2,176 @@
 //! to serve requests and do various other tasks.
 
 use async_trait::async_trait;
-use hyper::{Request, Response, Body, Method};
-use parking_lot::RwLock;
+use hyper::{Request, Response, Body, Method, HeaderMap};
+use parking_lot::{Mutex, RwLock};
 use serde::{Serialize, Deserialize};
 use tera::Context;
 use tokio::time::{Duration, Instant, interval, delay_for};
+use tokio_rustls::rustls::ServerConfig;
 
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::atomic::Ordering;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
 
-use crate::hyper_boilerplate::Respond;
+use crate::hyper_boilerplate::{Respond, Listener};
 use crate::utils;
 
 mod ctx;
-pub use ctx::Ctx;
-use ctx::log;
+pub use ctx::{Ctx, Users};
+use ctx::log::{self, Fields, Subsystem};
+use ctx::users::Role;
 
 mod error;
 use error::Result;
 
+mod alerts;
+use alerts::AlertState;
+
+mod api_tokens;
+use api_tokens::ApiToken;
+
+mod audit;
+
+mod check;
+pub use check::check;
+
+mod log_export;
+mod log_stream;
+
 mod lua;
 pub use lua::Backend as LuaBackend;
-use lua::sim::Sim;
+pub use lua::run_all as run_backends;
 
 mod templates;
-use templates::Templates;
 
 mod login;
+use login::{LoginToken, Attempts};
+
+mod network;
+mod offline;
+pub use offline::{sim_once, render, state_show, state_convert, state_diff};
+mod reload;
 mod responses;
 
+mod totp;
+use totp::Enrollment;
+
+mod session;
+use session::{Admin, Session};
+
+mod tls;
+
+mod world;
+use world::World;
+
 /// Contains all state used by the application in a
 /// concurrently-accessible format.
 pub struct AppState {
     /// When was the server initialized?
     start_time: Instant,
-    /// Contains data used to render templates.
-    templates: RwLock<Templates>,
     /// Tokens used by `/login` to authenticate the user.
-    login_tokens: RwLock<HashMap<u64, Instant>>,
-    /// Permits interaction with the task running the Lua renderer instance.
-    lua: lua::Frontend,
-    /// Permits interaction with the Lua simulation program.
-    sim: Sim,
+    login_tokens: RwLock<HashMap<u64, LoginToken>>,
+    /// Failed login attempts, indexed by the address of the client that made them.
+    login_attempts: RwLock<HashMap<IpAddr, Attempts>>,
+    /// The admin accounts that can log in.
+    users: Users,
+    /// Sessions created by logging in, indexed by their ID.
+    sessions: RwLock<HashMap<u64, Session>>,
+    /// API tokens that scripts can use instead of logging in, indexed by name.
+    api_tokens: RwLock<HashMap<String, ApiToken>>,
+    /// The TOTP enrollments of admin accounts, indexed by user name.
+    totp: RwLock<HashMap<String, Enrollment>>,
+    /// TOTP enrollments that have been started but not yet confirmed.
+    totp_pending: RwLock<HashMap<String, Enrollment>>,
+    /// The secret used to sign session cookies. It is regenerated every
+    /// time the server starts, which invalidates any existing sessions.
+    session_key: [u8; 32],
+    /// The secret from which unknown users' fake salts are derived. Unlike
+    /// `session_key`, this persists across restarts, like real users' salts.
+    salt_key: [u8; 32],
+    /// The ID that will be given to the next HTTP request, used to correlate log messages.
+    next_request_id: AtomicU64,
+    /// The state of each alert rule in the config file, in the same order.
+    alerts: RwLock<Vec<AlertState>>,
+    /// When was the config file last modified before it was last loaded?
+    cfg_modified: RwLock<Option<SystemTime>>,
+    /// Held while runtime settings are changed through the admin panel and while
+    /// the config is reloaded, so that a reload can't discard such a change.
+    runtime_lock: Mutex<()>,
+    /// The certificate used to serve HTTPS, if TLS is enabled and it has been loaded.
+    tls: RwLock<Option<tls::Loaded>>,
+    /// The worlds being served, starting with the main one. Shared pages
+    /// are rendered with the templates of the main world.
+    worlds: Vec<World>,
     /// Context data used throughout the application (config and logging).
+    /// This belongs to the main world.
     ctx: Ctx,
 }
 
 impl AppState {
-    /// Initialize the state.
-    pub fn new(ctx: Ctx) -> (LuaBackend, Self) {
-        let (frontend, backend) = lua::init(&ctx);
-        (backend, Self {
+    /// Initialize the state, along with the Lua backend of each world.
+    pub fn new(ctx: Ctx, users: Users) -> (Vec<LuaBackend>, Self) {
+        let names = ctx.cfg().worlds().map(|world| world.name.to_string()).collect::<Vec<_>>();
+        let (backends, mut app_state) = Self::with_worlds(ctx, &names);
+        app_state.users = users;
+        app_state.api_tokens = RwLock::new(api_tokens::load(&app_state.ctx));
+        app_state.totp = RwLock::new(totp::load(&app_state.ctx));
+        app_state.salt_key = login::load_salt_key(&app_state.ctx);
+        (backends, app_state)
+    }
+
+    /// Initialize the state needed to render pages of the context's world
+    /// from the command line. Only that world and the main one (whose templates
+    /// are used for error pages) are loaded, and nobody can log in.
+    pub fn offline(ctx: Ctx) -> (Vec<LuaBackend>, Self) {
+        let mut names = vec![ctx::cfg::MAIN_WORLD.to_string()];
+        if ctx.world_name() != ctx::cfg::MAIN_WORLD {
+            names.push(ctx.world_name().to_string());
+        }
+        Self::with_worlds(ctx, &names)
+    }
+
+    /// Initialize the state with the given worlds (starting with the main one),
+    /// but without any accounts or persisted authentication state.
+    fn with_worlds(ctx: Ctx, names: &[String]) -> (Vec<LuaBackend>, Self) {
+        let (backends, worlds) = names.iter()
+            .enumerate()
+            .map(|(i, name)| World::new(ctx.for_world(name), i))
+            .unzip();
+        (backends, Self {
             start_time: Instant::now(),
-            templates: RwLock::new(Templates::load(&ctx)),
             login_tokens: RwLock::default(),
-            lua: frontend,
-            sim: Sim::new(),
-            ctx,
+            login_attempts: RwLock::default(),
+            users: Users::default(),
+            sessions: RwLock::default(),
+            api_tokens: RwLock::default(),
+            totp: RwLock::default(),
+            totp_pending: RwLock::default(),
+            session_key: rand::random(),
+            salt_key: rand::random(),
+            next_request_id: AtomicU64::new(0),
+            alerts: RwLock::new(alerts::init(&ctx.cfg().alerts)),
+            cfg_modified: RwLock::new(reload::modified(&ctx.cfg())),
+            runtime_lock: Mutex::new(()),
+            tls: RwLock::default(),
+            worlds,
+            ctx: ctx.for_world(ctx::cfg::MAIN_WORLD),
         })
     }
 
     /// Perform various bookkeeping tasks at regular intervals.
     pub async fn do_scheduled(&self) {
-        let cfg = &self.ctx.cfg;
-        
         let mut interval = interval(Duration::from_secs(1));
         let mut i = 0u64;
         loop {
             interval.tick().await;
             i += 1;
+            // The config may have been reloaded since the last tick.
+            let cfg = self.ctx.cfg();
 
             macro_rules! at_interval {
                 ($t:expr => $body:expr) => {
@@ -79,11 +179,20 @@ impl AppState {
                 }
             }
 
-            at_interval!(cfg.runtime.template_refresh.load(Ordering::Relaxed)
-                => self.reload_templates());
-            at_interval!(cfg.runtime.sim_rate.load(Ordering::Relaxed)
-                => self.sim.run(self.ctx.clone()));
-            at_interval!(cfg.security.auth_sweep => self.clear_login_tokens());
+            for world in &self.worlds {
+                let runtime = world.ctx.world(&cfg).runtime;
+                at_interval!(runtime.template_refresh.load(Ordering::Relaxed)
+                    => world.reload_templates());
+                at_interval!(runtime.sim_rate.load(Ordering::Relaxed)
+                    => world.sim.run(world.ctx.clone()));
+            }
+            at_interval!(cfg.security.auth_sweep => {
+                self.clear_login_tokens();
+                self.clear_sessions();
+            });
+            at_interval!(alerts::CHECK_INTERVAL => self.check_alerts());
+            self.ctx.log.write_closed_duplicates();
+            at_interval!(reload::WATCH_INTERVAL => self.watch_config().await);
         }
     }
 
@@ -102,13 +211,23 @@ impl AppState {
 
     /// Generate a response to the given request. Wrap the response
     /// in `Ok(_)` if it was successful, and in `Err(_)` if it was not.
-    async fn try_respond(&self, req: Request<Body>) -> Result<Response<Body>> {
+    #[allow(clippy::result_large_err)]
+    async fn try_respond(
+        &self,
+        addr: SocketAddr,
+        listener: Listener,
+        req: Request<Body>,
+    ) -> Result<Response<Body>> {
+        if listener == Listener::Redirect {
+            return self.redirect_to_https(&req);
+        }
         self.delay().await;
         // Return an error if we somehow get a URI that doesn't have a path.
         let (head, body) = req.into_parts();
         let uri = head.uri.into_parts();
         let path_and_query = match uri.path_and_query {
-            None => self.error_500("request URL does not contain a path")?,
+            None => self.error_500_with(Fields::new(Subsystem::Http),
+                "request URL does not contain a path")?,
             Some(pnq) => pnq,
         };
 
@@ -121,91 +240,179 @@ impl AppState {
             .split('/')
             .collect::<Vec<_>>();
 
+        if !self.is_route_permitted(&path, addr.ip(), listener) {
+            return self.error_404();
+        }
+
         if head.method == Method::GET {
-            self.handle_get_request(&path, param).await
+            self.handle_get_request(&path, param, addr, &head.headers).await
         } else if head.method == Method::POST {
             let body = utils::read_body(body).await
-                .or_else(|e| self.error_500(format_args!(
+                .or_else(|e| self.error_500_with(Fields::new(Subsystem::Http), format_args!(
                     "could not read request body: {}",
                     e,
                 )))?;
-            self.handle_post_request(&path, body).await
+            self.handle_post_request(&path, body, addr, &head.headers).await
         } else {
             self.error_404()
         }
     }
 
     /// Generate a response to a GET request to the given path.
+    #[allow(clippy::result_large_err)]
     async fn handle_get_request(
         &self,
         path: &[&str],
         param: Option<String>,
+        addr: SocketAddr,
+        headers: &HeaderMap,
     ) -> Result<Response<Body>> {
         match path {
             ["static", file] => {
-                let file_path = path!(&self.ctx.cfg.paths.static_, "public", file);
+                let file_path = path!(&self.ctx.cfg().paths.static_, "public", file);
                 self.serve_file(&file_path).await
             }
             ["about"] => self.try_render("about.html", &Context::new()),
             ["login"] => {
-                let token = self.gen_login_token();
+                let token = self.gen_login_token(addr.ip());
                 let mut context = Context::new();
                 context.insert("token", &token);
                 self.render("login.html", &context)
             }
+            ["logout"] => Ok(self.end_session(headers, "/login")),
+            ["login", "salt"] => match param {
+                Some(name) => Ok(self.login_salt(&name)),
+                None => self.error_400(),
+            }
             ["blog"] => self.serve_blog_index(),
             ["blog", id] => {
                 self.try_render(&format!("blog/{}.html", id), &Context::new())
             }
-            ["admin", path @ ..] => self.handle_admin_get_request(path).await,
-            [ver, name] => if let Ok(ver) = ver.parse() {
-                self.lua.render(ver, String::from(*name), param).await
-                    .ok_or(())
-                    .or_else(|_| self.error_500("backend is not running"))
-            } else {
-                self.error_404()
+            ["admin", path @ ..] => match self.authenticate(headers) {
+                Some(admin) => self.handle_admin_get_request(path, param, &admin).await,
+                None if headers.contains_key(hyper::header::AUTHORIZATION) => self.error_401(),
+                None => Ok(Self::redirect("/login")),
+            }
+            path => match self.route_world(path, headers) {
+                (world, [ver, name]) => if let Ok(ver) = ver.parse() {
+                    world.lua.render(ver, String::from(*name), param).await
+                        .ok_or(())
+                        .or_else(|_| self.error_500("backend is not running"))
+                } else {
+                    self.error_404()
+                }
+                _ => self.error_404(),
             }
-            _ => self.error_404(),
         }
     }
 
     /// Generate a response to a GET request to a path that starts with `/admin`.
-    async fn handle_admin_get_request(&self, path: &[&str]) -> Result<Response<Body>> {
-        // TODO: put this behind some kind of authentication barrier
+    /// The caller is responsible for checking that the user is logged in.
+    #[allow(clippy::result_large_err)]
+    async fn handle_admin_get_request(
+        &self,
+        path: &[&str],
+        param: Option<String>,
+        admin: &Admin,
+    ) -> Result<Response<Body>> {
+        let (world, path) = self.admin_world(path).ok_or(()).or_else(|_| self.error_404())?;
         match path {
             ["static", file] => {
-                let file_path = path!(&self.ctx.cfg.paths.static_, "admin", file);
+                self.require(admin, Role::Viewer)?;
+                let file_path = path!(&self.ctx.cfg().paths.static_, "admin", file);
                 self.serve_file(&file_path).await
             }
             [] => {
+                self.require(admin, Role::Viewer)?;
                 let mut ctx = Context::new();
 
+                ctx.insert("csrf_token", &self.csrf_token(admin));
+                ctx.insert("user", &admin.name);
+                ctx.insert("role", &admin.role);
                 ctx.insert("num_blogs", &self.ctx.blog.ids().len());
-                ctx.insert("num_focuses", &self.lua.num_focuses(&self.ctx).await);
-                ctx.insert("num_templates", &self.num_templates());
-                ctx.insert("template_refresh",
-                    &self.ctx.cfg.runtime.template_refresh.load(Ordering::Relaxed));
-                ctx.insert("sim_file",
-                    &*self.ctx.cfg.runtime.sim_file.read());
-                ctx.insert("sim_rate",
-                    &self.ctx.cfg.runtime.sim_rate.load(Ordering::Relaxed));
-                ctx.insert("num_states", &self.lua.num_states(&self.ctx).await);
+                ctx.insert("worlds", &self.world_statuses().await);
+                ctx.insert("config", &self.ctx.cfg().effective(&self.ctx.log));
+                ctx.insert("tls", &self.tls_status());
                 ctx.insert("uptime", &self.start_time.elapsed().as_secs());
+                ctx.insert("lockouts", &self.lockouts());
+                ctx.insert("alerts", &self.alert_statuses());
 
                 self.render("admin/index.html", &ctx)
             }
+            ["audit"] => {
+                self.require(admin, Role::Viewer)?;
+                let page = param.and_then(|p| p.parse().ok()).unwrap_or(0);
+                let (records, num_pages) = self.read_audit(page);
+
+                let mut ctx = Context::new();
+                ctx.insert("records", &records);
+                ctx.insert("page", &page);
+                ctx.insert("num_pages", &num_pages);
+
+                self.render("admin/audit.html", &ctx)
+            }
+            ["log", "stream"] => {
+                self.require(admin, Role::Viewer)?;
+                let param = param.unwrap_or_else(|| String::from("{}"));
+                match log::Filter::from_body(param.as_bytes()) {
+                    Some(filter) => self.serve_log_stream(filter),
+                    None => self.error_400(),
+                }
+            }
+            ["log", "export", format] => {
+                self.require(admin, Role::Viewer)?;
+                let param = param.unwrap_or_else(|| String::from("{}"));
+                let filter = log::Filter::from_body(param.as_bytes());
+                match (filter, log_export::Format::from_name(format)) {
+                    (Some(filter), Some(format)) => self.serve_log_export(filter, format),
+                    _ => self.error_400(),
+                }
+            }
+            ["sessions"] => {
+                self.require(admin, Role::Owner)?;
+                let mut ctx = Context::new();
+
+                ctx.insert("csrf_token", &self.csrf_token(admin));
+                ctx.insert("sessions", &self.list_sessions(admin));
+
+                self.render("admin/sessions.html", &ctx)
+            }
+            ["totp"] => {
+                self.require_account(admin)?;
+                let mut ctx = Context::new();
+
+                ctx.insert("csrf_token", &self.csrf_token(admin));
+                ctx.insert("user", &admin.name);
+                ctx.insert("enabled", &self.has_totp(&admin.name));
+
+                self.render("admin/totp.html", &ctx)
+            }
+            ["tokens"] => {
+                self.require(admin, Role::Owner)?;
+                let mut ctx = Context::new();
+
+                ctx.insert("csrf_token", &self.csrf_token(admin));
+                ctx.insert("tokens", &self.list_api_tokens());
+
+                self.render("admin/tokens.html", &ctx)
+            }
             ["sim_files"] => {
+                self.require(admin, Role::Owner)?;
                 let mut ctx = Context::new();
+                let cfg = self.ctx.cfg();
 
-                ctx.insert("files", &lua::sim::list_files(&self.ctx));
+                ctx.insert("csrf_token", &self.csrf_token(admin));
+                ctx.insert("world", world.ctx.world_name());
+                ctx.insert("files", &lua::sim::list_files(&world.ctx));
                 ctx.insert("active",
-                    &*self.ctx.cfg.runtime.sim_file.read());
+                    &*world.ctx.world(&cfg).runtime.sim_file.read());
 
                 self.render("admin/sim_files.html", &ctx)
             }
             ["sim_files", name] => {
-                if lua::sim::is_valid_name(&name) {
-                    let path = self.ctx.cfg.paths.sim.join(name);
+                self.require(admin, Role::Owner)?;
+                if lua::sim::is_valid_name(name) {
+                    let path = world.ctx.world(&self.ctx.cfg()).sim.join(name);
                     self.serve_file(&path).await
                 } else {
                     self.error_404()
@@ -220,39 +427,100 @@ impl AppState {
         &self,
         path: &[&str],
         body: Vec<u8>,
+        addr: SocketAddr,
+        headers: &HeaderMap,
     ) -> Result<Response<Body>> {
         match path {
-            ["login"] => self.login(body),
-            ["admin", path @ ..] => self.handle_admin_post_request(path, body).await,
+            ["login"] => self.login(addr.ip(), body),
+            ["admin", path @ ..] => match self.authenticate(headers) {
+                Some(admin) => {
+                    self.check_csrf(&admin, headers)?;
+                    self.handle_admin_post_request(path, body, addr, &admin).await
+                }
+                None => self.error_401(),
+            }
             _ => self.error_404(),
         }
     }
 
     /// Generate a response to a POST request to a path that starts with `/admin`.
+    /// The caller is responsible for checking that the user is logged in.
+    #[allow(clippy::result_large_err)]
     async fn handle_admin_post_request(
         &self,
         path: &[&str],
         body: Vec<u8>,
+        addr: SocketAddr,
+        admin: &Admin,
     ) -> Result<Response<Body>> {
-        // TODO: put this behind some kind of authentication barrier
+        let (world, path) = self.admin_world(path).ok_or(()).or_else(|_| self.error_404())?;
         match path {
             ["reload_blog"] => {
+                self.require(admin, Role::Operator)?;
+                let old = self.ctx.blog.ids().len();
                 self.ctx.reload_blog();
+                let new = self.ctx.blog.ids().len();
+                self.audit(admin, addr, "reload_blog", Some(old), Some(new));
                 Ok(Self::empty_200())
             }
             ["reload_templates"] => {
-                self.reload_templates();
+                self.require(admin, Role::Operator)?;
+                let old = world.num_templates();
+                world.reload_templates();
+                let new = world.num_templates();
+                self.audit(admin, addr, &world.audit_action("reload_templates"),
+                    Some(old), Some(new));
+                Ok(Self::empty_200())
+            }
+            ["reload_config"] => {
+                self.require(admin, Role::Owner)?;
+                match self.reload_config().await {
+                    Some(changes) => {
+                        self.audit(admin, addr, "reload_config",
+                            None::<&str>,
+                            Some(format!("{} changes", changes)));
+                        Ok(Self::empty_200())
+                    }
+                    None => Self::text_error(500, "the config file could not be reloaded"),
+                }
+            }
+            ["reload_tls"] => {
+                self.require(admin, Role::Owner)?;
+                if self.ctx.cfg().tls.is_none() {
+                    return Self::text_error(400, "TLS is not enabled");
+                }
+                let old = self.tls_loaded_time();
+                if !self.load_tls() {
+                    return Self::text_error(500, "the certificate could not be loaded");
+                }
+                self.audit(admin, addr, "reload_tls", old, self.tls_loaded_time());
                 Ok(Self::empty_200())
             }
             ["reload_focuses"] => {
-                self.lua.reload_focuses(&self.ctx).await;
+                self.require(admin, Role::Operator)?;
+                let old = world.lua.num_focuses(&world.ctx).await;
+                world.lua.reload_focuses(&world.ctx).await;
+                let new = world.lua.num_focuses(&world.ctx).await;
+                self.audit(admin, addr, &world.audit_action("reload_focuses"),
+                    Some(old), Some(new));
                 Ok(Self::empty_200())
             }
             ["update_template_refresh"] => {
+                self.require(admin, Role::Operator)?;
                 if let Some(new) = utils::parse_bytes(body) {
-                    let old = self.ctx.cfg.runtime.template_refresh.swap(new, Ordering::Relaxed);
+                    let _guard = self.runtime_lock.lock();
+                    let cfg = self.ctx.cfg();
+                    let old = world.ctx.world(&cfg).runtime.template_refresh
+                        .swap(new, Ordering::Relaxed);
                     if new != old {
-                        self.ctx.log.info(format_args!("changed template refresh to {}", new));
+                        self.ctx.log.with(world.ctx.fields(Subsystem::Templates)).info(format_args!(
+                            "'{}' changed template refresh to {}",
+                            admin.name,
+                            new,
+                        ));
+                        self.audit(admin, addr, &world.audit_action("update_template_refresh"),
+                            Some(old), Some(new));
+                        cfg.save_runtime(&self.ctx.log);
                     }
                     Ok(Self::empty_200())
                 } else {
@@ -260,10 +528,20 @@ impl AppState {
                 }
             }
             ["update_sim_rate"] => {
+                self.require(admin, Role::Operator)?;
                 if let Some(new) = utils::parse_bytes(body) {
-                    let old = self.ctx.cfg.runtime.sim_rate.swap(new, Ordering::Relaxed);
+                    let _guard = self.runtime_lock.lock();
+                    let cfg = self.ctx.cfg();
+                    let old = world.ctx.world(&cfg).runtime.sim_rate.swap(new, Ordering::Relaxed);
                     if new != old {
-                        self.ctx.log.info(format_args!("changed sim rate to {}", new));
+                        self.ctx.log.with(world.ctx.fields(Subsystem::Sim)).info(format_args!(
+                            "'{}' changed sim rate to {}",
+                            admin.name,
+                            new,
+                        ));
+                        self.audit(admin, addr, &world.audit_action("update_sim_rate"),
+                            Some(old), Some(new));
+                        cfg.save_runtime(&self.ctx.log);
                     }
                     Ok(Self::empty_200())
                 } else {
@@ -271,18 +549,51 @@ impl AppState {
                 }
             }
             ["delete_message"] => {
-                if let Some(idx) = utils::parse_bytes(body) {
-                    self.ctx.log.toggle_deleted(idx);
+                self.require(admin, Role::Operator)?;
+                if let Some(id) = utils::parse_bytes(body) {
+                    if let Some(is_deleted) = self.ctx.log.toggle_deleted(id) {
+                        /// Describe whether message `id` is deleted.
+                        fn describe(id: u64, is_deleted: bool) -> String {
+                            let state = if is_deleted { "deleted" } else { "visible" };
+                            format!("message {} {}", id, state)
+                        }
+                        self.audit(admin, addr, "delete_message",
+                            Some(describe(id, !is_deleted)),
+                            Some(describe(id, is_deleted)));
+                    }
                     Ok(Self::empty_200())
                 } else {
                     self.error_400()
                 }
             }
-            ["filter_log"] => self.serve_filter_log(&body),
+            ["filter_log"] => {
+                self.require(admin, Role::Viewer)?;
+                self.serve_filter_log(&body)
+            }
             ["update_sim_file"] => {
+                self.require(admin, Role::Owner)?;
                 if let Ok(body) = String::from_utf8(body) {
                     if lua::sim::is_valid_name(&body) {
-                        *self.ctx.cfg.runtime.sim_file.write() = body.into();
+                        let new = PathBuf::from(body);
+                        let _guard = self.runtime_lock.lock();
+                        let cfg = self.ctx.cfg();
+                        let old = std::mem::replace(
+                            &mut *world.ctx.world(&cfg).runtime.sim_file.write(),
+                            new.clone(),
+                        );
+                        if new != old {
+                            let fields = world.ctx.fields(Subsystem::Sim)
+                                .sim_file(new.display().to_string());
+                            self.ctx.log.with(fields).info(format_args!(
+                                "'{}' changed sim file to '{}'",
+                                admin.name,
+                                new.display(),
+                            ));
+                            self.audit(admin, addr, &world.audit_action("update_sim_file"),
+                                Some(old.display()),
+                                Some(new.display()));
+                            cfg.save_runtime(&self.ctx.log);
+                        }
                         Ok(Self::empty_200())
                     } else {
                         self.error_400()
@@ -291,6 +602,117 @@ impl AppState {
                     self.error_400()
                 }
             }
+            ["sim_files", name] => {
+                self.require(admin, Role::Owner)?;
+                if !lua::sim::is_valid_name(name) {
+                    return self.error_400();
+                }
+                let path = world.ctx.world(&self.ctx.cfg()).sim.join(name);
+                let old = std::fs::read(&path).ok().map(|c| utils::sha256_bytes(&c));
+                let new = utils::sha256_bytes(&body);
+                let fields = world.ctx.fields(Subsystem::Sim).sim_file(*name);
+                if let Err(e) = std::fs::write(&path, body) {
+                    return self.error_500_with(fields, format_args!(
+                        "could not write sim file '{}': {}",
+                        path.display(),
+                        e,
+                    ));
+                }
+                self.ctx.log.with(fields)
+                    .info(format_args!("'{}' uploaded sim file '{}'", admin.name, name));
+                let action = world.audit_action(&format!("upload_sim_file {}", name));
+                self.audit(admin, addr, &action, old, Some(new));
+                Ok(Self::empty_200())
+            }
+            ["sessions", "revoke"] => {
+                self.require(admin, Role::Owner)?;
+                let id = utils::parse_bytes(body).ok_or(()).or_else(|_| self.error_400())?;
+                if let Some(owner) = self.revoke_session(id) {
+                    self.ctx.log.info(format_args!(
+                        "'{}' revoked a session belonging to '{}'",
+                        admin.name,
+                        owner,
+                    ));
+                    self.audit(admin, addr, "revoke_session", Some(owner), None::<&str>);
+                    Ok(Self::empty_200())
+                } else {
+                    self.error_404()
+                }
+            }
+            ["totp", "begin"] => {
+                self.require_account(admin)?;
+                let info = self.begin_totp(&admin.name);
+                let json = serde_json::to_string(&info)
+                    .or_else(|e| self.error_500(format_args!(
+                        "could not serialize TOTP enrollment: {}",
+                        e,
+                    )))?;
+                Ok(Response::builder()
+                    .status(200)
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json))
+                    .unwrap())
+            }
+            ["totp", "confirm"] => {
+                self.require_account(admin)?;
+                let code = String::from_utf8(body).or_else(|_| self.error_400())?;
+                if self.confirm_totp(&admin.name, &code) {
+                    self.ctx.log.info(format_args!("user '{}' enabled TOTP", admin.name));
+                    self.audit(admin, addr, "enable_totp", None::<&str>, Some(&admin.name));
+                    Ok(Self::empty_200())
+                } else {
+                    self.error_400()
+                }
+            }
+            ["totp", "disable"] => {
+                self.require_account(admin)?;
+                let code = String::from_utf8(body).or_else(|_| self.error_400())?;
+                if self.disable_totp(&admin.name, &code) {
+                    self.ctx.log.info(format_args!("user '{}' disabled TOTP", admin.name));
+                    self.audit(admin, addr, "disable_totp", Some(&admin.name), None::<&str>);
+                    Ok(Self::empty_200())
+                } else {
+                    self.error_400()
+                }
+            }
+            ["tokens", "mint"] => {
+                self.require(admin, Role::Owner)?;
+
+                /// Describes the format of requests to mint a token.
+                #[derive(Deserialize)]
+                struct MintData {
+                    /// The name of the new token.
+                    name: String,
+                    /// What the new token permits its holder to do.
+                    scope: Role,
+                }
+
+                let MintData { name, scope } = serde_json::from_slice(&body)
+                    .or_else(|_| self.error_400())?;
+                if !api_tokens::is_valid_name(&name) || scope > admin.role {
+                    return self.error_400();
+                }
+                let token = self.mint_api_token(admin, name.clone(), scope).ok_or(())
+                    .or_else(|_| Self::text_error(409, "a token with that name already exists"))?;
+                self.ctx.log.info(format_args!("'{}' minted API token '{}'", admin.name, name));
+                self.audit(admin, addr, "mint_api_token", None::<&str>, Some(&name));
+                Ok(Response::builder()
+                    .status(200)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from(token))
+                    .unwrap())
+            }
+            ["tokens", "revoke"] => {
+                self.require(admin, Role::Owner)?;
+                let name = String::from_utf8(body).or_else(|_| self.error_400())?;
+                if self.revoke_api_token(&name) {
+                    self.ctx.log.info(format_args!("'{}' revoked API token '{}'", admin.name, name));
+                    self.audit(admin, addr, "revoke_api_token", Some(&name), None::<&str>);
+                    Ok(Self::empty_200())
+                } else {
+                    self.error_404()
+                }
+            }
             _ => self.error_404(),
         }
     }
@@ -298,12 +720,13 @@ impl AppState {
     /// Simulate a connection with high latency by waiting for a number of
     /// milliseconds specified in the config file.
     async fn delay(&self) {
-        if let Some(latency) = self.ctx.cfg.latency {
+        if let Some(latency) = self.ctx.cfg().latency {
             delay_for(Duration::from_millis(latency as u64)).await
         }
     }
 
     /// Generate a response to a GET request to the path "/blog".
+    #[allow(clippy::result_large_err)]
     fn serve_blog_index(&self) -> Result<Response<Body>> {
         /// Describes how posts are serialized when passing them to Tera.
         #[derive(Serialize)]
@@ -336,38 +759,49 @@ impl AppState {
     }
 
     /// Generate a response to a POST request to the path "/admin/filter_log".
+    #[allow(clippy::result_large_err)]
     fn serve_filter_log(&self, body: &[u8]) -> Result<Response<Body>> {
-        /// Describes how log messages are serialized when passing them to Tera.
-        #[derive(Serialize)]
-        struct TeraLogMsg {
-            /// The index of the message in the log.
-            idx: usize,
-            /// The rest of the fields associated with messages.
-            #[serde(flatten)]
-            msg: log::Message,
-        }
-        let filter = log::Filter::from_body(body);
-        let mut messages = Vec::new();
-        self.ctx.log.for_each(|idx, msg| {
-            if filter.permits(msg) {
-                messages.push(TeraLogMsg { idx, msg: msg.clone() });
-            }
-        });
+        let filter = match log::Filter::from_body(body) {
+            Some(filter) => filter,
+            None => return self.error_400(),
+        };
+        let (messages, before) = self.ctx.log.query(&filter);
         let mut context = Context::new();
         context.insert("messages", &messages);
+        // If there are more results, tell the client where the next page starts.
+        if let Some(before) = before {
+            context.insert("before", &before);
+        }
         self.render("admin/filtered_log.html", &context)
     }
 }
 
 #[async_trait]
 impl Respond for AppState {
-    async fn respond(&self, _: SocketAddr, req: Request<Body>) -> Response<Body> {
-        match self.try_respond(req).await {
+    async fn respond(
+        &self,
+        addr: SocketAddr,
+        listener: Listener,
+        req: Request<Body>,
+    ) -> Response<Body> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let resp = log::REQUEST_ID.scope(request_id, self.try_respond(addr, listener, req)).await;
+        let mut resp = match resp {
             Ok(resp) => resp,
             Err(resp) => resp,
-        }
+        };
+        resp.headers_mut().insert("X-Request-Id", request_id.into());
+        resp
+    }
+    fn tls_config(&self, listener: Listener) -> Option<Arc<ServerConfig>> {
+        self.tls_config_for(listener)
     }
-    fn shutdown_on_err(&self, err: hyper::Error) {
-        self.ctx.log.err(format_args!("hyper shut down: {}", err))
+    fn connection_failed(&self, addr: Option<SocketAddr>, err: io::Error) {
+        let log = self.ctx.log.of(Subsystem::Http);
+        match addr {
+            // Failed handshakes are routine (e.g. clients that don't trust the certificate).
+            Some(addr) => log.status(format_args!("TLS handshake with {} failed: {}", addr, err)),
+            None => log.err(format_args!("could not accept connection: {}", err)),
+        }
     }
 }
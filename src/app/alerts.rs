@@ -0,0 +1,213 @@
+//! Raises alerts when the server seems to be misbehaving, so that problems
+//! are noticed even when nobody is watching the admin panel.
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::process::Command;
+use std::thread;
+
+use super::ctx::cfg::{AlertCondition, AlertRule};
+
+/// How frequently (in seconds) are alert rules checked?
+pub const CHECK_INTERVAL: u32 = 5;
+
+/// Tracks the state of a single alert rule.
+#[derive(Default)]
+pub struct AlertState {
+    /// Is the situation described by the rule currently occurring?
+    active: bool,
+    /// The most recently measured number of errors or failed ticks.
+    value: u64,
+    /// When was the alert last raised?
+    last_raised: Option<DateTime<Utc>>,
+    /// How many times has the alert been raised since the server started?
+    times_raised: u64,
+}
+
+/// Describes how alert rules are serialized when passing them to Tera.
+#[derive(Serialize)]
+pub struct TeraAlert {
+    /// The name of the rule.
+    name: String,
+    /// A description of the situation in which the alert is raised.
+    condition: String,
+    /// The most recently measured number of errors or failed ticks.
+    value: u64,
+    /// Is the situation currently occurring?
+    active: bool,
+    /// When was the alert last raised?
+    last_raised: Option<String>,
+    /// How many times has the alert been raised since the server started?
+    times_raised: u64,
+}
+
+/// Return the number that the measured value of an alert is compared with.
+fn threshold(condition: &AlertCondition) -> u64 {
+    match *condition {
+        AlertCondition::ErrorRate { count, .. } => count,
+        AlertCondition::SimFailures { count } => count,
+    }
+}
+
+/// Return whether a measured value is enough for an alert to be raised:
+/// more than `count` errors, or at least `count` failed ticks in a row.
+fn is_reached(condition: &AlertCondition, value: u64) -> bool {
+    match *condition {
+        AlertCondition::ErrorRate { count, .. } => value > count,
+        AlertCondition::SimFailures { count } => value >= count,
+    }
+}
+
+/// Describe the situation in which an alert is raised.
+fn describe(condition: &AlertCondition) -> String {
+    match *condition {
+        AlertCondition::ErrorRate { count, minutes } =>
+            format!("more than {} errors in {} min", count, minutes),
+        AlertCondition::SimFailures { count } =>
+            format!("{} failed sim ticks in a row", count),
+    }
+}
+
+/// Describe the situation that caused an alert to be raised.
+fn summarize(rule: &AlertRule, value: u64) -> String {
+    let measured = match rule.condition {
+        AlertCondition::ErrorRate { minutes, .. } =>
+            format!("{} errors in the last {} min", value, minutes),
+        AlertCondition::SimFailures { .. } =>
+            format!("{} failed sim ticks in a row", value),
+    };
+    format!("alert '{}': {} (threshold: {})", rule.name, measured, threshold(&rule.condition))
+}
+
+/// Create the initial state of each alert rule.
+pub fn init(rules: &[AlertRule]) -> Vec<AlertState> {
+    rules.iter().map(|_| AlertState::default()).collect()
+}
+
+impl super::AppState {
+    /// Measure the situation described by each alert rule, and raise
+    /// the alerts whose thresholds have been reached.
+    pub(super) fn check_alerts(&self) {
+        let now = Utc::now();
+        let rules = &self.ctx.cfg().alerts;
+        let mut raised = Vec::new();
+
+        for (rule, state) in rules.iter().zip(self.alerts.write().iter_mut()) {
+            state.value = match rule.condition {
+                AlertCondition::ErrorRate { minutes, .. } => self.ctx.log
+                    .count_errors_since(now - Duration::minutes(minutes as i64)),
+                AlertCondition::SimFailures { .. } => self.worlds.iter()
+                    .map(|world| world.sim.consecutive_failures())
+                    .max()
+                    .unwrap_or(0),
+            };
+
+            let was_active = state.active;
+            state.active = is_reached(&rule.condition, state.value);
+            if !state.active {
+                continue
+            }
+
+            // Don't repeat an alert for a situation that is still ongoing
+            // unless the cooldown has passed.
+            let cooled_down = state.last_raised
+                .is_none_or(|time| (now - time).num_seconds() >= rule.cooldown as i64);
+            if !was_active || cooled_down {
+                state.last_raised = Some(now);
+                state.times_raised += 1;
+                raised.push((rule, summarize(rule, state.value)));
+            }
+        }
+
+        for (rule, summary) in raised {
+            self.raise_alert(rule, summary, now);
+        }
+    }
+
+    /// Log an alert, append it to the rule's file and run the rule's command.
+    fn raise_alert(&self, rule: &AlertRule, summary: String, now: DateTime<Utc>) {
+        self.ctx.log.info(format_args!("raised {}", summary));
+
+        if let Some(path) = &rule.file {
+            let res = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "[{}] {}", now.to_rfc3339(), summary));
+            if let Err(e) = res {
+                self.ctx.log.err(format_args!(
+                    "could not write alert to '{}': {}",
+                    path.display(),
+                    e,
+                ));
+            }
+        }
+
+        if let Some(command) = &rule.command {
+            // Wait for the command in a separate thread so that it can't hold up
+            // the scheduler (and doesn't become a zombie once it exits).
+            let command = command.clone();
+            let ctx = self.ctx.clone();
+            let res = thread::Builder::new()
+                .name("alert".into())
+                .spawn(move || {
+                    let status = Command::new("sh")
+                        .arg("-c")
+                        .arg(&command)
+                        .env("NOKEVAIR_ALERT", &summary)
+                        .status();
+                    match status {
+                        Ok(status) if status.success() => {}
+                        Ok(status) => ctx.log.err(format_args!(
+                            "alert command '{}' failed: {}",
+                            command,
+                            status,
+                        )),
+                        Err(e) => ctx.log.err(format_args!(
+                            "could not run alert command '{}': {}",
+                            command,
+                            e,
+                        )),
+                    }
+                });
+            if let Err(e) = res {
+                self.ctx.log.err(format_args!("could not start alert thread: {}", e));
+            }
+        }
+    }
+
+    /// Return the state of each alert rule.
+    pub(super) fn alert_statuses(&self) -> Vec<TeraAlert> {
+        self.ctx.cfg().alerts.iter()
+            .zip(self.alerts.read().iter())
+            .map(|(rule, state)| TeraAlert {
+                name: rule.name.clone(),
+                condition: describe(&rule.condition),
+                value: state.value,
+                active: state.active,
+                last_raised: state.last_raised.map(|time| time.to_rfc3339()),
+                times_raised: state.times_raised,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds() {
+        let errors = AlertCondition::ErrorRate { count: 20, minutes: 10 };
+        assert!(!is_reached(&errors, 19));
+        assert!(!is_reached(&errors, 20));
+        assert!(is_reached(&errors, 21));
+
+        let failures = AlertCondition::SimFailures { count: 3 };
+        assert!(!is_reached(&failures, 2));
+        assert!(is_reached(&failures, 3));
+    }
+}
@@ -0,0 +1,151 @@
+//! Utilities for minting, revoking and checking the API tokens that let
+//! scripts access the admin panel without logging in through a browser.
+//!
+//! Only the SHA256 hash of each token is stored, in a JSON file on disk.
+//! The token itself is shown to the admin once, when it is minted.
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::fs;
+
+use super::{Ctx, utils};
+use super::ctx::users::Role;
+use super::session::Admin;
+use super::ctx::log::Subsystem;
+
+/// The string that every API token begins with.
+const TOKEN_PREFIX: &str = "nkv_";
+
+/// How stale (in seconds) may the time a token was last used get before it is
+/// updated? This lets most requests check their token without a write lock.
+const LAST_USED_RESOLUTION: i64 = 5;
+
+/// Tracks information about a single API token.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    /// The name given to the token when it was minted.
+    pub name: String,
+    /// What the token permits its holder to do.
+    pub scope: Role,
+    /// The name of the admin who minted the token.
+    pub created_by: String,
+    /// When was the token minted?
+    pub created: DateTime<Utc>,
+    /// When was the token last used (since the server started)?
+    #[serde(skip)]
+    pub last_used: Option<DateTime<Utc>>,
+    /// The SHA256 hash of the token.
+    hash: String,
+}
+
+/// Read the API tokens from the file named in the config. If it doesn't
+/// exist yet, there are no tokens.
+pub fn load(ctx: &Ctx) -> HashMap<String, ApiToken> {
+    let path = &ctx.cfg().paths.api_tokens;
+    let contents = match fs::read(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            ctx.log.of(Subsystem::Auth).err(format_args!("while reading API tokens: {}", e));
+            return HashMap::new()
+        }
+    };
+    match serde_json::from_slice::<Vec<ApiToken>>(&contents) {
+        Ok(tokens) => tokens.into_iter().map(|t| (t.name.clone(), t)).collect(),
+        Err(e) => {
+            ctx.log.of(Subsystem::Auth).err(format_args!("while parsing API tokens: {}", e));
+            HashMap::new()
+        }
+    }
+}
+
+/// Determine whether a particular string is a valid name for an API token.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl super::AppState {
+    /// Write the current set of API tokens to disk.
+    fn save_api_tokens(&self, tokens: &HashMap<String, ApiToken>) {
+        let mut tokens = tokens.values().collect::<Vec<_>>();
+        tokens.sort_by(|a, b| a.name.cmp(&b.name));
+        let path = &self.ctx.cfg().paths.api_tokens;
+        let res = serde_json::to_vec_pretty(&tokens)
+            .map_err(std::io::Error::from)
+            .and_then(|json| fs::write(path, json));
+        if let Err(e) = res {
+            self.ctx.log.of(Subsystem::Auth).err(format_args!(
+                "could not write API tokens to '{}': {}",
+                path.display(),
+                e,
+            ));
+        }
+    }
+
+    /// Create a new API token and return it, or `None` if a token with the same
+    /// name already exists.
+    pub(super) fn mint_api_token(&self, admin: &Admin, name: String, scope: Role) -> Option<String> {
+        let mut tokens = self.api_tokens.write();
+        if tokens.contains_key(&name) {
+            return None;
+        }
+
+        let token = format!("{}{}", TOKEN_PREFIX, hex::encode(rand::random::<[u8; 24]>()));
+        tokens.insert(name.clone(), ApiToken {
+            name,
+            scope,
+            created_by: admin.name.clone(),
+            created: Utc::now(),
+            last_used: None,
+            hash: utils::sha256(&token),
+        });
+        self.save_api_tokens(&tokens);
+        Some(token)
+    }
+
+    /// Revoke the API token with the given name. Return whether it existed.
+    pub(super) fn revoke_api_token(&self, name: &str) -> bool {
+        let mut tokens = self.api_tokens.write();
+        let existed = tokens.remove(name).is_some();
+        if existed {
+            self.save_api_tokens(&tokens);
+        }
+        existed
+    }
+
+    /// Return all API tokens, ordered by name.
+    pub(super) fn list_api_tokens(&self) -> Vec<ApiToken> {
+        let mut tokens = self.api_tokens.read().values().cloned().collect::<Vec<_>>();
+        tokens.sort_by(|a, b| a.name.cmp(&b.name));
+        tokens
+    }
+
+    /// If the given string is a valid API token, return an admin with its scope.
+    pub(super) fn authenticate_api_token(&self, token: &str) -> Option<Admin> {
+        if !token.starts_with(TOKEN_PREFIX) {
+            return None;
+        }
+        let hash = utils::sha256(token);
+        let now = Utc::now();
+        let (name, scope, stale) = {
+            let tokens = self.api_tokens.read();
+            let api_token = tokens.values()
+                .find(|t| utils::constant_time_eq(&t.hash, &hash))?;
+            let stale = api_token.last_used
+                .is_none_or(|last_used| (now - last_used).num_seconds() >= LAST_USED_RESOLUTION);
+            (api_token.name.clone(), api_token.scope, stale)
+        };
+        if stale {
+            // The token may have been revoked in the meantime.
+            if let Some(api_token) = self.api_tokens.write().get_mut(&name) {
+                if api_token.hash == hash {
+                    api_token.last_used = api_token.last_used.max(Some(now));
+                }
+            }
+        }
+        self.ctx.log.of(Subsystem::Auth).status(format_args!("API token '{}' was used", name));
+        Some(Admin::for_api_token(&name, scope))
+    }
+}
@@ -0,0 +1,111 @@
+//! Maintains an append-only record of the changes made through the admin panel,
+//! stored on disk as one JSON object per line.
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use std::fs::{self, OpenOptions};
+use std::io::Write as _;
+use std::net::SocketAddr;
+
+use super::lua::Version;
+use super::session::Admin;
+
+/// The number of records shown on each page of the audit log.
+const PAGE_SIZE: usize = 100;
+
+/// Represents a single action recorded in the audit log.
+#[derive(Serialize, Deserialize)]
+pub struct Record {
+    /// When was the action taken?
+    pub time: DateTime<Utc>,
+    /// The name of the admin who took the action.
+    pub actor: String,
+    /// The address of the client that the request came from.
+    pub addr: String,
+    /// What kind of action was it (e.g. `update_sim_rate`)?
+    pub action: String,
+    /// The value of whatever was changed before the action, if applicable.
+    pub old: Option<String>,
+    /// The value of whatever was changed after the action, if applicable.
+    pub new: Option<String>,
+    /// The latest version of the state when the action was taken, if there was one.
+    pub version: Option<usize>,
+}
+
+impl super::AppState {
+    /// Append a record of an action taken by an admin to the audit log.
+    pub(super) fn audit<O: ToString, N: ToString>(
+        &self,
+        admin: &Admin,
+        addr: SocketAddr,
+        action: &str,
+        old: Option<O>,
+        new: Option<N>,
+    ) {
+        let record = Record {
+            time: Utc::now(),
+            actor: admin.name.clone(),
+            addr: addr.to_string(),
+            action: action.to_string(),
+            old: old.map(|o| o.to_string()),
+            new: new.map(|n| n.to_string()),
+            version: Version::next_available(&self.ctx).previous().map(Version::as_usize),
+        };
+
+        let mut line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                self.ctx.log.err(format_args!("could not serialize audit record: {}", e));
+                return
+            }
+        };
+        line.push('\n');
+
+        let path = &self.ctx.cfg().paths.audit;
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            // Write the whole line at once so that concurrent records don't interleave.
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = res {
+            self.ctx.log.err(format_args!(
+                "could not write to audit log '{}': {}",
+                path.display(),
+                e,
+            ));
+        }
+    }
+
+    /// Read one page of the audit log, newest records first. Also return
+    /// the total number of pages.
+    pub(super) fn read_audit(&self, page: usize) -> (Vec<Record>, usize) {
+        let path = &self.ctx.cfg().paths.audit;
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            // The file is only created once the first action is taken.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                self.ctx.log.err(format_args!(
+                    "could not read audit log '{}': {}",
+                    path.display(),
+                    e,
+                ));
+                String::new()
+            }
+        };
+
+        let lines = contents.lines().filter(|l| !l.is_empty()).collect::<Vec<_>>();
+        let num_pages = lines.len().div_ceil(PAGE_SIZE);
+
+        let mut records = Vec::new();
+        for line in lines.iter().rev().skip(page * PAGE_SIZE).take(PAGE_SIZE) {
+            match serde_json::from_str(line) {
+                Ok(record) => records.push(record),
+                Err(e) => self.ctx.log.err(format_args!("malformed audit record: {}", e)),
+            }
+        }
+        (records, num_pages.max(1))
+    }
+}
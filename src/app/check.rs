@@ -0,0 +1,210 @@
+//! Validates a deployment without serving it, so that problems can be
+//! found before the server is restarted (`nokevair check Config.toml`).
+
+use std::fs::{self, File};
+use std::path::Path;
+
+use crate::conv;
+use crate::utils::SourceChain;
+use super::{Ctx, Users};
+use super::ctx::cfg::MAIN_WORLD;
+use super::ctx::log::Subsystem;
+use super::lua::{self, Version};
+use super::templates::Templates;
+use super::tls;
+
+/// Run every check, logging each problem that is found as an error.
+/// Return whether no problems were found, including while the config was loaded.
+pub fn check(ctx: &Ctx) -> bool {
+    check_paths(ctx);
+    check_blog(ctx);
+    Users::load(&ctx.log, &ctx.cfg());
+    check_tls(ctx);
+    let worlds = ctx.cfg().worlds().map(|world| world.name.to_string()).collect::<Vec<_>>();
+    for world in worlds {
+        let ctx = ctx.for_world(&world);
+        check_world_paths(&ctx);
+        check_focuses(&ctx);
+        Templates::load(&ctx);
+        check_sim_file(&ctx);
+        check_latest_state(&ctx);
+    }
+
+    let problems = ctx.log.count_errors();
+    if problems == 0 {
+        ctx.log.info("check passed");
+        true
+    } else {
+        ctx.log.err(format_args!(
+            "check failed with {} error{}",
+            problems,
+            if problems == 1 { "" } else { "s" },
+        ));
+        false
+    }
+}
+
+/// Check that the shared directories in the `[paths]` section exist, along
+/// with the directories containing the files that are written at runtime.
+fn check_paths(ctx: &Ctx) {
+    let paths = &ctx.cfg().paths;
+    let dirs = [
+        ("templates", &paths.templates),
+        ("static", &paths.static_),
+    ];
+    for (name, dir) in dirs.iter() {
+        if !dir.is_dir() {
+            ctx.log.err(format_args!(
+                "paths.{}: '{}' is not a directory",
+                name,
+                dir.display(),
+            ));
+        }
+    }
+
+    let files = [
+        ("audit", &paths.audit),
+        ("api-tokens", &paths.api_tokens),
+        ("totp", &paths.totp),
+        ("salt-key", &paths.salt_key),
+    ];
+    for (name, file) in files.iter() {
+        let parent = file.parent().filter(|p| *p != Path::new("")).unwrap_or(Path::new("."));
+        if !parent.is_dir() {
+            ctx.log.err(format_args!(
+                "paths.{}: directory '{}' does not exist",
+                name,
+                parent.display(),
+            ));
+        }
+    }
+}
+
+/// Check that the renderer, simulation and state directories of the context's world exist.
+fn check_world_paths(ctx: &Ctx) {
+    let cfg = ctx.cfg();
+    let world = ctx.world(&cfg);
+    let dirs = [("render", world.render), ("sim", world.sim), ("state", world.state)];
+    for (name, dir) in dirs.iter() {
+        if !dir.is_dir() {
+            let key = if world.name == MAIN_WORLD {
+                format!("paths.{}", name)
+            } else {
+                format!("world '{}': {}", world.name, name)
+            };
+            ctx.log.err(format_args!("{}: '{}' is not a directory", key, dir.display()));
+        }
+    }
+}
+
+/// Check that every `focus.lua` evaluates to a function
+/// and has a matching `format.html.tera`.
+fn check_focuses(ctx: &Ctx) {
+    let lua = lua::create_lua_state(ctx);
+    lua::render::with_entries(ctx, |name, path| {
+        let log = ctx.log.with(ctx.fields(Subsystem::Render).focus(name.as_str()));
+
+        let format = path.join("format.html.tera");
+        if !format.is_file() {
+            log.err(format_args!("'{}' does not exist", format.display()));
+        }
+
+        let focus = path.join("focus.lua");
+        let code = match fs::read_to_string(&focus) {
+            Ok(code) => code,
+            Err(e) => {
+                log.err(format_args!("failed to read file '{}': {}", focus.display(), e));
+                return
+            }
+        };
+        let res = lua.context(|ctx| {
+            ctx.load(&code)
+                .set_name(&focus.display().to_string())?
+                .eval::<rlua::Function>()
+                .map(drop)
+        });
+        if let Err(e) = res {
+            log.err(format_args!(
+                "lua ('{}' -> focus):\n{}",
+                focus.display(),
+                SourceChain(e),
+            ));
+        }
+    });
+}
+
+/// Check that every post in the blog manifest has a template.
+fn check_blog(ctx: &Ctx) {
+    let log = ctx.log.of(Subsystem::Templates);
+    for id in ctx.blog.ids().iter() {
+        let path = ctx.cfg().paths.templates.join(format!("blog/{}.html.tera", id));
+        if !path.is_file() {
+            log.err(format_args!("blog post '{}': '{}' does not exist", id, path.display()));
+        }
+    }
+}
+
+/// Check that the TLS certificate and key can be loaded, if TLS is enabled.
+fn check_tls(ctx: &Ctx) {
+    if let Some(tls) = &ctx.cfg().tls {
+        let log = ctx.log.of(Subsystem::Http);
+        if let Err(e) = tls::load_config(tls) {
+            log.err(format_args!("tls: {}", e));
+        }
+        for host in &tls.hosts {
+            if !tls::is_valid_host(host) {
+                log.err(format_args!("tls.hosts: '{}' is not a host name", host));
+            }
+        }
+    }
+}
+
+/// Check that the configured simulation file evaluates to a function.
+fn check_sim_file(ctx: &Ctx) {
+    let cfg = ctx.cfg();
+    let world = ctx.world(&cfg);
+    let sim_file = world.runtime.sim_file.read().clone();
+    let log = ctx.log.with(ctx.fields(Subsystem::Sim).sim_file(sim_file.display().to_string()));
+    let path = world.sim.join(&sim_file);
+    let code = match fs::read_to_string(&path) {
+        Ok(code) => code,
+        Err(e) => {
+            log.err(format_args!(
+                "could not read simulation code in '{}': {}",
+                path.display(),
+                e,
+            ));
+            return
+        }
+    };
+    let res = lua::create_lua_state(ctx).context(|lua_ctx| {
+        lua_ctx.load(&code)
+            .set_name(&path.display().to_string())?
+            .eval::<rlua::Function>()
+            .map(drop)
+    });
+    if let Err(e) = res {
+        log.err(format_args!("lua ('{}' -> sim):\n{}", path.display(), SourceChain(e)));
+    }
+}
+
+/// Check that the newest state file can be decoded as MessagePack.
+fn check_latest_state(ctx: &Ctx) {
+    let ver = match Version::next_available(ctx).previous() {
+        Some(ver) => ver,
+        None => {
+            ctx.log.with(ctx.fields(Subsystem::Render)).info("no state files found");
+            return
+        }
+    };
+    let log = ctx.log.with(ctx.fields(Subsystem::Render).version(ver.as_usize()));
+    let path = ver.path(ctx);
+    let res = File::open(&path).and_then(|mut file| conv::bytes_to_msgpack(&mut file));
+    if let Err(e) = res {
+        log.err(format_args!(
+            "'{}' could not be read as msgpack: {}",
+            path.display(),
+            e,
+        ));
+    }
+}
@@ -5,43 +5,101 @@
 //! In cases where this would be shadowed by the RLua context, it is instead called
 //! `app_ctx`.
 
+use parking_lot::RwLock;
+
+use std::path::Path;
 use std::sync::Arc;
 
 mod blog;
 pub use blog::Blog;
 
-mod cfg;
+pub mod cfg;
 pub use cfg::Cfg;
+use cfg::World;
 
 pub mod log;
 pub use log::Log;
+use log::{Fields, Subsystem};
+
+pub mod users;
+pub use users::Users;
 
-/// Provides a shared, cloneable handle to the log and config information.
+/// Provides a shared, cloneable handle to the blog, the log and config
+/// information. Each context also belongs to a world, whose renderer,
+/// simulation and state files it uses.
 #[derive(Clone)]
 pub struct Ctx {
     /// A handle to the blog descriptor.
     pub blog: Arc<Blog>,
-    /// A handle to the app configuration.
-    pub cfg: Arc<Cfg>,
+    /// A handle to the app configuration, which is replaced when it is reloaded.
+    cfg: Arc<RwLock<Arc<Cfg>>>,
     /// A handle to the log.
     pub log: Arc<Log>,
+    /// The name of the world that this context belongs to.
+    world: Arc<str>,
 }
 
 impl Ctx {
-    /// Initialize the context, loading config from a command-line argument.
-    pub fn load() -> Option<Self> {
+    /// Initialize the context, loading config from the given file.
+    /// Unless `persist_log` is set, messages are only printed, even if the
+    /// config specifies a log file.
+    pub fn load(cfg_path: &Path, persist_log: bool) -> Option<Self> {
         let log = Log::new();
-        let cfg = Cfg::load(&log)?;
+        let cfg = Cfg::load(&log, &cfg::enter_dir(&log, cfg_path))?;
+        log.configure(&cfg.log);
+        if persist_log {
+            log.persist(&cfg.log);
+        }
         let blog = Blog::load(&log, &cfg)?;
         Some(Self {
             blog: Arc::new(blog),
-            cfg: Arc::new(cfg),
+            cfg: Arc::new(RwLock::new(Arc::new(cfg))),
             log: Arc::new(log),
+            world: cfg::MAIN_WORLD.into(),
         })
     }
+
+    /// Return a copy of this context that belongs to another world.
+    pub fn for_world(&self, name: &str) -> Self {
+        Self { world: name.into(), ..self.clone() }
+    }
+
+    /// Return the name of the world that this context belongs to.
+    pub fn world_name(&self) -> &str {
+        &self.world
+    }
+
+    /// Return the settings of this context's world from a config. The set of
+    /// worlds can't change while the server runs, but if the world is somehow
+    /// missing, the main world is used instead.
+    pub fn world<'a>(&self, cfg: &'a Cfg) -> World<'a> {
+        cfg.world(&self.world).unwrap_or_else(|| cfg.main_world())
+    }
+
+    /// Create a set of log fields describing a subsystem, and this
+    /// context's world unless it is the main one.
+    pub fn fields(&self, subsystem: Subsystem) -> Fields {
+        let fields = Fields::new(subsystem);
+        if &*self.world == cfg::MAIN_WORLD {
+            fields
+        } else {
+            fields.world(&*self.world)
+        }
+    }
     
+    /// Return the current app configuration. Since the configuration may be
+    /// reloaded at any time, avoid holding onto it for longer than necessary.
+    pub fn cfg(&self) -> Arc<Cfg> {
+        Arc::clone(&self.cfg.read())
+    }
+
+    /// Replace the app configuration with one that was reloaded.
+    pub fn set_cfg(&self, cfg: Cfg) {
+        *self.cfg.write() = Arc::new(cfg);
+    }
+
     /// Convenience function to invoke `self.blog.reload` with appropriate parameters.
     pub fn reload_blog(&self) {
-        self.blog.reload(&self.log, &self.cfg);
+        self.blog.reload(&self.log, &self.cfg());
     }
 }
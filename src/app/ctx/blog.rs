@@ -105,12 +105,12 @@ impl Blog {
     }
 
     /// Return a handle to a vector containing the IDs of all posts.
-    pub fn ids(&self) -> RwLockReadGuard<Vec<String>> {
+    pub fn ids(&self) -> RwLockReadGuard<'_, Vec<String>> {
         self.ids.read().unwrap_or_else(PoisonError::into_inner)
     }
 
     /// Return a handle to the metadata associated with a particular post.
-    pub fn metadata(&self, id: &str) -> Option<chashmap::ReadGuard<String, Post>> {
+    pub fn metadata(&self, id: &str) -> Option<chashmap::ReadGuard<'_, String, Post>> {
         self.posts.get(id)
     }
 }
@@ -1,19 +1,22 @@
 //! Defines the format of `Config.toml` files.
 
 use parking_lot::RwLock;
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
 
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
 use std::env;
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use super::Log;
+use super::log::MessageKind;
 
 /// Contains all config information (deserialized from a TOML file given as
 /// a command-line argument).
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Cfg {
     /// The `addr` field of the config file.
     pub addr: SocketAddr,
@@ -25,11 +28,183 @@ pub struct Cfg {
     pub paths: Paths,
     /// The `[security]` section of the config file.
     pub security: Security,
+    /// The `[network]` section of the config file.
+    #[serde(default)]
+    pub network: Network,
+    /// The `[tls]` section of the config file. If it is present, HTTPS is
+    /// served instead of plain HTTP.
+    pub tls: Option<Tls>,
+    /// The `[log]` section of the config file.
+    #[serde(default)]
+    pub log: LogCfg,
+    /// The `[[alerts]]` sections of the config file.
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,
+    /// The `[[worlds]]` sections of the config file, which describe the
+    /// worlds served alongside the main one.
+    #[serde(default)]
+    pub worlds: Vec<WorldCfg>,
+    /// Describes where each field that wasn't left at its default came from,
+    /// indexed by its dotted path (e.g. `runtime.sim-rate`).
+    #[serde(skip)]
+    sources: HashMap<String, String>,
+    /// The absolute path of the config file, from which it is reloaded.
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+/// Describes a field whose value differs between two configs.
+pub struct Change {
+    /// The dotted path of the field, e.g. `runtime.sim-rate`.
+    pub key: String,
+    /// The old value of the field, in TOML syntax, or `None` if it was absent.
+    pub old: Option<String>,
+    /// The new value of the field, in TOML syntax, or `None` if it is absent.
+    pub new: Option<String>,
+}
+
+/// Describes a field of the effective config and where its value came from.
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    /// The dotted path of the field, e.g. `runtime.sim-rate`.
+    pub key: String,
+    /// The value of the field, in TOML syntax.
+    pub value: String,
+    /// Where the value came from: the config file, an environment variable,
+    /// the runtime settings file, or the default.
+    pub source: String,
+}
+
+/// The prefix of the environment variables that override config fields.
+const ENV_PREFIX: &str = "NOKEVAIR_";
+
+/// The tables whose fields can be overridden by environment variables, along
+/// with the part of the variable names (after `ENV_PREFIX`) that selects them.
+/// Longer prefixes come first so that `NETWORK_ALLOW_` isn't mistaken for `NETWORK_`.
+const ENV_TABLES: &[(&str, &[&str])] = &[
+    ("NETWORK_ALLOW_", &["network", "allow"]),
+    ("NETWORK_", &["network"]),
+    ("RUNTIME_", &["runtime"]),
+    ("PATHS_", &["paths"]),
+    ("SECURITY_", &["security"]),
+    ("TLS_", &["tls"]),
+    ("LOG_", &["log"]),
+];
+
+/// The top-level fields that can be overridden by environment variables.
+const ENV_FIELDS: &[&str] = &["addr", "latency"];
+
+/// Return the dotted path of the field overridden by an environment variable,
+/// e.g. `["runtime", "sim-rate"]` for `NOKEVAIR_RUNTIME_SIM_RATE`.
+fn env_field(var: &str) -> Option<Vec<String>> {
+    let name = var.strip_prefix(ENV_PREFIX)?;
+    let field = |s: &str| s.to_lowercase().replace('_', "-");
+    for (prefix, table) in ENV_TABLES {
+        if let Some(rest) = name.strip_prefix(prefix) {
+            let mut path: Vec<String> = table.iter().map(|s| s.to_string()).collect();
+            path.push(field(rest));
+            return Some(path);
+        }
+    }
+    Some(vec![field(name)]).filter(|path| ENV_FIELDS.contains(&path[0].as_str()))
+}
+
+/// Parse the value of an environment variable. If the config file gives the
+/// field a string, the value is used as is; otherwise it is parsed as TOML,
+/// falling back to a string if the field is absent from the config file.
+fn parse_env_value(existing: Option<&toml::Value>, raw: &str) -> Result<toml::Value, String> {
+    if let Some(toml::Value::String(_)) = existing {
+        return Ok(toml::Value::String(raw.to_string()));
+    }
+    match toml::from_str::<toml::value::Table>(&format!("value = {}", raw)) {
+        Ok(mut table) => table.remove("value").ok_or_else(|| String::from("missing value")),
+        Err(_) if existing.is_none() => Ok(toml::Value::String(raw.to_string())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Override fields of a parsed config file with `NOKEVAIR_*` environment
+/// variables (given as name-value pairs), recording which variable each field
+/// came from. Return whether every value could be applied; unrecognized
+/// variables are only reported.
+fn apply_env<I>(
+    cfg: &mut toml::value::Table,
+    sources: &mut HashMap<String, String>,
+    vars: I,
+    log: &Log,
+) -> bool
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut ok = true;
+    let mut vars = vars.into_iter()
+        .filter(|(var, _)| var.starts_with(ENV_PREFIX))
+        .collect::<Vec<_>>();
+    vars.sort();
+
+    'vars: for (var, raw) in vars {
+        let path = match env_field(&var) {
+            Some(path) => path,
+            None => {
+                log.err(format_args!("unrecognized environment variable '{}'", var));
+                continue
+            }
+        };
+        let (field, tables) = path.split_last().expect("paths are never empty");
+
+        // Find the table containing the field, creating it if necessary.
+        let mut table = &mut *cfg;
+        for name in tables {
+            let entry = table.entry(name.clone())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+            table = match entry.as_table_mut() {
+                Some(table) => table,
+                None => {
+                    log.err(format_args!("while applying '{}': '{}' is not a table", var, name));
+                    ok = false;
+                    continue 'vars
+                }
+            };
+        }
+
+        match parse_env_value(table.get(field), &raw) {
+            Ok(value) => {
+                table.insert(field.clone(), value);
+                sources.insert(path.join("."), format!("env {}", var));
+            }
+            Err(e) => {
+                log.err(format_args!("while applying '{}': {}", var, e));
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// Flatten a TOML value into a list of dotted paths and the values they lead to.
+/// Arrays of tables (like `[[alerts]]`) are indexed by position.
+fn flatten(prefix: String, value: &toml::Value, out: &mut Vec<(String, String)>) {
+    let join = |key: &str| {
+        if prefix.is_empty() { key.to_string() } else { format!("{}.{}", prefix, key) }
+    };
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                flatten(join(key), value, out);
+            }
+        }
+        toml::Value::Array(array) if array.iter().any(toml::Value::is_table) => {
+            for (i, value) in array.iter().enumerate() {
+                flatten(join(&i.to_string()), value, out);
+            }
+        }
+        value => out.push((prefix, value.to_string())),
+    }
 }
 
 /// Represents parts of the config that are mutably shared so they can
 /// be configured at runtime via the admin panel.
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Runtime {
     /// How frequently do we reload templates? If this is zero, then never.
     #[serde(rename="template-refresh")]
@@ -40,29 +215,200 @@ pub struct Runtime {
     /// How frequently do we run the simulation? If this is zero, then never.
     #[serde(rename="sim-rate")]
     pub sim_rate: AtomicU32,
+    /// The values given in the config file, before any overrides were applied.
+    #[serde(skip)]
+    config: RuntimeValues,
+}
+
+impl Clone for Runtime {
+    fn clone(&self) -> Self {
+        Self {
+            template_refresh: AtomicU32::new(self.template_refresh.load(Ordering::Relaxed)),
+            sim_file: RwLock::new(self.sim_file.read().clone()),
+            sim_rate: AtomicU32::new(self.sim_rate.load(Ordering::Relaxed)),
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// A snapshot of some or all of the runtime settings. Settings that
+/// were changed through the admin panel are stored in this format.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RuntimeValues {
+    /// How frequently do we reload templates?
+    #[serde(rename="template-refresh", skip_serializing_if = "Option::is_none")]
+    pub template_refresh: Option<u32>,
+    /// The path to the file containing the Lua simulation code.
+    #[serde(rename="sim-file", skip_serializing_if = "Option::is_none")]
+    pub sim_file: Option<PathBuf>,
+    /// How frequently do we run the simulation?
+    #[serde(rename="sim-rate", skip_serializing_if = "Option::is_none")]
+    pub sim_rate: Option<u32>,
+}
+
+/// The format of the runtime settings file. The main world's settings are at
+/// the top level, and those of other worlds are in `[worlds.<name>]` tables.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RuntimeFile {
+    /// The settings of the main world.
+    #[serde(flatten)]
+    main: RuntimeValues,
+    /// The settings of other worlds, indexed by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    worlds: BTreeMap<String, RuntimeValues>,
+}
+
+impl Runtime {
+    /// Return the current value of every setting.
+    fn current(&self) -> RuntimeValues {
+        RuntimeValues {
+            template_refresh: Some(self.template_refresh.load(Ordering::Relaxed)),
+            sim_file: Some(self.sim_file.read().clone()),
+            sim_rate: Some(self.sim_rate.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Return the value of every setting given in the config file.
+    pub fn config(&self) -> &RuntimeValues {
+        &self.config
+    }
+
+    /// Return the settings whose current values differ from the config file.
+    pub fn overrides(&self) -> RuntimeValues {
+        let current = self.current();
+        RuntimeValues {
+            template_refresh: current.template_refresh
+                .filter(|v| Some(v) != self.config.template_refresh.as_ref()),
+            sim_file: current.sim_file.filter(|v| Some(v) != self.config.sim_file.as_ref()),
+            sim_rate: current.sim_rate.filter(|v| Some(v) != self.config.sim_rate.as_ref()),
+        }
+    }
+
+    /// Change the settings that are present in `overrides`.
+    fn apply(&self, overrides: RuntimeValues) {
+        if let Some(v) = overrides.template_refresh {
+            self.template_refresh.store(v, Ordering::Relaxed);
+        }
+        if let Some(v) = overrides.sim_file {
+            *self.sim_file.write() = v;
+        }
+        if let Some(v) = overrides.sim_rate {
+            self.sim_rate.store(v, Ordering::Relaxed);
+        }
+    }
 }
 
 /// The part of the config that provides paths to directories where certain
 /// files are found and stored.
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Paths {
     /// The TOML file describing blog posts.
     pub blog: PathBuf,
-    /// Renderer files.
+    /// Renderer files of the main world.
     pub render: PathBuf,
-    /// Simulation files.
+    /// Simulation files of the main world.
     pub sim: PathBuf,
-    /// State files.
+    /// State files of the main world.
     pub state: PathBuf,
     /// Templates that aren't part of the renderer.
     pub templates: PathBuf,
     /// Static files.
     #[serde(rename="static")]
     pub static_: PathBuf,
+    /// The TOML file describing admin accounts.
+    pub users: PathBuf,
+    /// The file to which changes made through the admin panel are recorded.
+    pub audit: PathBuf,
+    /// The file in which API tokens are stored.
+    #[serde(rename="api-tokens")]
+    pub api_tokens: PathBuf,
+    /// The file in which TOTP enrollments are stored.
+    pub totp: PathBuf,
+    /// The file in which runtime settings changed through the admin panel are stored.
+    #[serde(default = "default_runtime_path")]
+    pub runtime: PathBuf,
+    /// The file holding the secret from which unknown users' fake salts are
+    /// derived. It is generated if it doesn't exist.
+    #[serde(rename="salt-key", default = "default_salt_key_path")]
+    pub salt_key: PathBuf,
+}
+
+/// The name of the world described by the `[runtime]` section and `[paths]`.
+pub const MAIN_WORLD: &str = "main";
+
+/// The first segments of paths that are routed to something other than the
+/// main world, and so can't be used as the prefix of another world.
+const RESERVED_PREFIXES: &[&str] = &["static", "about", "login", "logout", "blog", "admin"];
+
+/// Describes a world that is served alongside the main one, with its own
+/// renderer, simulation and state files. Templates outside the renderer,
+/// static files, the blog and admin accounts are shared by every world.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorldCfg {
+    /// Identifies the world in the log, the admin panel and the runtime settings file.
+    pub name: String,
+    /// If present, the world is served from paths under `/<prefix>`, which must
+    /// not be purely numeric. `/<prefix>` itself has no page.
+    pub prefix: Option<String>,
+    /// If present, the world is served to requests whose `Host` header
+    /// names this host, in place of the main world.
+    pub host: Option<String>,
+    /// Renderer files.
+    pub render: PathBuf,
+    /// Simulation files.
+    pub sim: PathBuf,
+    /// State files.
+    pub state: PathBuf,
+    /// The `[worlds.runtime]` section, which works like the top-level `[runtime]`.
+    pub runtime: Runtime,
+}
+
+/// The settings of a single world, borrowed from the config. This describes
+/// the main world and those listed in `[[worlds]]` alike.
+#[derive(Clone, Copy)]
+pub struct World<'a> {
+    /// The name of the world.
+    pub name: &'a str,
+    /// The path prefix under which the world is served, if any.
+    pub prefix: Option<&'a str>,
+    /// The host to which the world is served, if any.
+    pub host: Option<&'a str>,
+    /// Renderer files.
+    pub render: &'a Path,
+    /// Simulation files.
+    pub sim: &'a Path,
+    /// State files.
+    pub state: &'a Path,
+    /// The runtime settings of the world.
+    pub runtime: &'a Runtime,
+}
+
+impl<'a> From<&'a WorldCfg> for World<'a> {
+    fn from(world: &'a WorldCfg) -> Self {
+        Self {
+            name: &world.name,
+            prefix: world.prefix.as_deref(),
+            host: world.host.as_deref(),
+            render: &world.render,
+            sim: &world.sim,
+            state: &world.state,
+            runtime: &world.runtime,
+        }
+    }
+}
+
+/// Used as the default location of the runtime settings file.
+fn default_runtime_path() -> PathBuf {
+    PathBuf::from("runtime.toml")
+}
+
+/// Used as the default location of the salt key file.
+fn default_salt_key_path() -> PathBuf {
+    PathBuf::from("salt_key")
 }
 
 /// The part of the config that provides various parameters relating to authentication.
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Security {
     /// For how many seconds is a login challenge token considered valid?
     #[serde(rename="auth-timeout")]
@@ -70,48 +416,691 @@ pub struct Security {
     /// How frequently do we sweep the login challenge token list for outdated entries?
     #[serde(rename="auth-sweep")]
     pub auth_sweep: u32,
-    /// The password used to access the admin panel.
-    /// (This is not read from the config file, but instead via environment variable.)
-    #[serde(skip)]
-    pub login_password: Option<String>,
+    /// For how many seconds does an admin session remain valid after logging in?
+    #[serde(rename="session-timeout")]
+    pub session_timeout: u32,
+    /// How many login challenge tokens may a single client hold at once?
+    #[serde(rename="max-tokens-per-ip")]
+    pub max_tokens_per_ip: u32,
+    /// For how many seconds must a client wait after its first failed login attempt?
+    /// (This doubles with every subsequent failure.)
+    #[serde(rename="backoff-base")]
+    pub backoff_base: u32,
+    /// After how many consecutive failed login attempts is a client locked out?
+    #[serde(rename="lockout-threshold")]
+    pub lockout_threshold: u32,
+    /// For how many seconds is a client locked out?
+    #[serde(rename="lockout-duration")]
+    pub lockout_duration: u32,
+}
+
+/// The part of the config that describes how log messages are stored.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct LogCfg {
+    /// The JSON-lines file that messages are written to. If this is absent,
+    /// messages are only kept in memory.
+    pub file: Option<PathBuf>,
+    /// After how many bytes is the log file rotated?
+    #[serde(rename="max-size")]
+    pub max_size: u64,
+    /// After how many seconds is the log file rotated?
+    #[serde(rename="max-age")]
+    pub max_age: u64,
+    /// How many rotated log files are kept?
+    pub retain: u32,
+    /// How many messages are reloaded from the log files on startup?
+    pub reload: usize,
+    /// The maximum number of messages kept in memory.
+    #[serde(rename="max-messages")]
+    pub max_messages: usize,
+    /// The maximum number of bytes that messages kept in memory may occupy.
+    #[serde(rename="max-bytes")]
+    pub max_bytes: usize,
+    /// For how many seconds after a message is logged are duplicates collapsed into it?
+    #[serde(rename="dedup-window")]
+    pub dedup_window: u64,
+    /// The least important kind of message that is printed to stderr.
+    #[serde(rename="stderr-level")]
+    pub stderr_level: Level,
+    /// How messages are printed to stderr.
+    #[serde(rename="stderr-format")]
+    pub stderr_format: StderrFormat,
+    /// The least important kind of message that is written to the log file.
+    #[serde(rename="file-level")]
+    pub file_level: Level,
+    /// The least important kind of message that is kept in memory
+    /// (and shown in the admin panel).
+    #[serde(rename="memory-level")]
+    pub memory_level: Level,
+}
+
+/// Selects which messages are sent to a particular destination. Each level
+/// includes the messages of every level before it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// No messages.
+    Off,
+    /// Only error messages.
+    Error,
+    /// Informational messages.
+    Info,
+    /// Debug messages generated by Lua code.
+    Lua,
+    /// Confirmations of frequent/routine actions, i.e. every message.
+    Status,
+}
+
+impl Level {
+    /// Check whether messages of a given kind are included at this level.
+    pub fn permits(self, kind: MessageKind) -> bool {
+        let level = match kind {
+            MessageKind::Error => Self::Error,
+            MessageKind::Info => Self::Info,
+            MessageKind::Lua => Self::Lua,
+            MessageKind::Status => Self::Status,
+        };
+        level <= self
+    }
+}
+
+/// Describes how messages are printed to stderr.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StderrFormat {
+    /// Use `color` if stderr is a terminal and `plain` otherwise.
+    Auto,
+    /// Human-readable lines with ANSI-coloured labels.
+    Color,
+    /// Human-readable lines without escape sequences.
+    Plain,
+    /// One JSON object per line, in the same format as the log file.
+    Json,
+}
+
+impl Default for LogCfg {
+    fn default() -> Self {
+        Self {
+            file: None,
+            max_size: 10 * 1024 * 1024,
+            max_age: 7 * 24 * 60 * 60,
+            retain: 5,
+            reload: 1000,
+            max_messages: 10_000,
+            max_bytes: 16 * 1024 * 1024,
+            dedup_window: 60,
+            stderr_level: Level::Status,
+            stderr_format: StderrFormat::Auto,
+            file_level: Level::Status,
+            memory_level: Level::Status,
+        }
+    }
+}
+
+/// Describes when an alert is raised and what happens when it is.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AlertRule {
+    /// Identifies the rule in the admin panel and in alert summaries.
+    pub name: String,
+    /// The situation in which the alert is raised.
+    #[serde(flatten)]
+    pub condition: AlertCondition,
+    /// A shell command to run when the alert is raised. The summary of the
+    /// alert is passed in the `NOKEVAIR_ALERT` environment variable.
+    pub command: Option<String>,
+    /// A file to which the summary of the alert is appended when it is raised.
+    pub file: Option<PathBuf>,
+    /// For how many seconds after an alert is raised is it not raised again
+    /// (as long as the situation persists)?
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+}
+
+/// Used as the default cooldown of alert rules.
+fn default_cooldown() -> u64 {
+    3600
+}
+
+/// A situation in which an alert is raised.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "when", rename_all = "kebab-case")]
+pub enum AlertCondition {
+    /// More than `count` error messages were logged in the last `minutes` minutes.
+    ErrorRate {
+        /// How many errors may be logged without raising the alert?
+        count: u64,
+        /// Over how many minutes?
+        minutes: u64,
+    },
+    /// The last `count` simulation ticks of any world failed.
+    SimFailures {
+        /// How many ticks must fail in a row?
+        count: u64,
+    },
+}
+
+/// The part of the config that describes which clients may access which routes.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Network {
+    /// If present, serve `/login`, `/logout` and `/admin` only from this address
+    /// rather than from `addr`.
+    #[serde(rename="admin-addr")]
+    pub admin_addr: Option<SocketAddr>,
+    /// The `[network.allow]` section of the config file.
+    #[serde(default)]
+    pub allow: Allow,
+}
+
+/// Lists the ranges of addresses that may access each group of routes. If a group
+/// is not listed, any address may access it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Allow {
+    /// Routes that are not part of another group.
+    pub public: Option<Vec<Cidr>>,
+    /// `/login` and `/logout`.
+    pub login: Option<Vec<Cidr>>,
+    /// `/admin` and everything under it.
+    pub admin: Option<Vec<Cidr>>,
 }
 
-/// Attempt to read the admin password from the `PW` environment variable.
-fn get_admin_password(log: &Log) -> Option<String> {
-    let pw = env::var("PW").ok();
-    if pw.is_none() {
-        log.info("specify admin password via PW environment variable to enable admin login");
+/// A range of IP addresses in CIDR notation, like `10.0.0.0/8` or `::1/128`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(try_from="String", into="String")]
+pub struct Cidr {
+    /// The first address in the range.
+    addr: IpAddr,
+    /// The number of leading bits that addresses in the range share.
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Check whether the range contains the given address. IPv4 addresses
+    /// mapped into IPv6 are treated as IPv4.
+    pub fn contains(self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
     }
-    pw
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse()
+            .map_err(|_| format!("invalid address in CIDR range '{}'", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse().ok().filter(|&len| len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in CIDR range '{}'", s))?,
+            None => max_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        format!("{}/{}", cidr.addr, cidr.prefix_len)
+    }
+}
+
+/// Where the certificate used to serve HTTPS is found, and how plain HTTP
+/// requests are treated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tls {
+    /// The PEM file containing the certificate chain, starting with the server's certificate.
+    pub cert: PathBuf,
+    /// The PEM file containing the private key (in PKCS #8 or RSA format).
+    pub key: PathBuf,
+    /// If present, plain HTTP requests to this address are redirected to HTTPS.
+    #[serde(rename="redirect-addr")]
+    pub redirect_addr: Option<SocketAddr>,
+    /// The names (without a port) by which clients reach the server, which
+    /// plain HTTP requests may be redirected to in addition to `addr`'s IP.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+/// Change directory to the location of the config file so that `Paths` is
+/// relative to it, and return the path of the file from there. This is done
+/// once, before the config is first loaded.
+pub fn enter_dir(log: &Log, path: &Path) -> PathBuf {
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if let Some(containing_dir) = path.parent() {
+        if containing_dir != Path::new("") {
+            if let Err(e) = env::set_current_dir(containing_dir) {
+                log.err(format_args!("could not cd to '{}': {}", containing_dir.display(), e));
+            }
+        }
+    } else {
+        log.err("impossible - config file has no parent dir");
+    }
+    path
 }
 
 impl Cfg {
-    /// Load config from the TOML file passed as a command-line argument.
-    pub fn load(log: &Log) -> Option<Self> {
-        let mut args = env::args().skip(1);
-        let path = PathBuf::from(match args.next() {
-            Some(p) => p,
-            None => { log.err("no config file specified"); return None }
-        });
-        let contents = match fs::read(&path) {
+    /// Load config from a TOML file. Relative paths in it are resolved from the
+    /// current directory, which should have been set with `enter_dir`.
+    pub fn load(log: &Log, path: &Path) -> Option<Self> {
+        let contents = match fs::read(path) {
             Ok(c) => c,
             Err(e) => { log.err(format_args!("while reading config file: {}", e)); return None }
         };
-        let mut self_: Self = match toml::from_slice(&contents) {
+        let table: toml::value::Table = match toml::from_slice(&contents) {
+            Ok(t) => t,
+            Err(e) => { log.err(format_args!("while parsing config file: {}", e)); return None }
+        };
+
+        let mut self_ = Self::from_table(log, table, env::vars())?;
+        self_.path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if !self_.check_worlds(log) {
+            return None
+        }
+        self_.runtime.config = self_.runtime.current();
+        for world in &mut self_.worlds {
+            world.runtime.config = world.runtime.current();
+        }
+        self_.load_runtime(log);
+        Some(self_)
+    }
+
+    /// Deserialize a parsed config file after overriding its fields with
+    /// environment variables (given as name-value pairs).
+    fn from_table<I>(log: &Log, mut table: toml::value::Table, vars: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        // Record which fields were given in the file, then let the environment override them.
+        let mut fields = Vec::new();
+        flatten(String::new(), &toml::Value::Table(table.clone()), &mut fields);
+        let mut sources = fields.into_iter()
+            .map(|(key, _)| (key, String::from("config file")))
+            .collect();
+        if !apply_env(&mut table, &mut sources, vars, log) {
+            return None
+        }
+
+        let mut self_: Self = match toml::Value::Table(table).try_into() {
             Ok(s) => s,
             Err(e) => { log.err(format_args!("while parsing config file: {}", e)); return None }
         };
-        self_.security.login_password = get_admin_password(log);
-        // Change directory to the location of the config file so that `Paths` is relative to it
-        if let Some(containing_dir) = path.parent() {
-            if containing_dir != Path::new("") {
-                if let Err(e) = env::set_current_dir(containing_dir) {
-                    log.err(format_args!("could not cd to '{}': {}", containing_dir.display(), e));
+        self_.sources = sources;
+        self_.report_unknown_env(log);
+        Some(self_)
+    }
+
+    /// Report the fields set by environment variables that don't exist in the
+    /// config, since deserializing it silently ignores them.
+    fn report_unknown_env(&self, log: &Log) {
+        let fields = self.fields(log);
+        let mut unknown = self.sources.iter()
+            .filter(|(_, source)| source.starts_with("env "))
+            .filter(|(key, _)| !fields.iter().any(|(field, _)| {
+                field == *key
+                    || field.strip_prefix(key.as_str()).is_some_and(|rest| rest.starts_with('.'))
+            }))
+            .map(|(_, source)| &source["env ".len()..])
+            .collect::<Vec<_>>();
+        unknown.sort();
+        for var in unknown {
+            log.err(format_args!("unrecognized environment variable '{}'", var));
+        }
+    }
+
+    /// Check that every world has a unique name and can be reached
+    /// by a unique prefix or host.
+    fn check_worlds(&self, log: &Log) -> bool {
+        let mut ok = true;
+        let mut err = |msg: std::fmt::Arguments| {
+            log.err(msg);
+            ok = false;
+        };
+        let is_valid = |s: &str| {
+            !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+        for (i, world) in self.worlds.iter().enumerate() {
+            let earlier = &self.worlds[..i];
+            if !is_valid(&world.name) || world.name == MAIN_WORLD {
+                err(format_args!("worlds.{}: invalid name '{}'", i, world.name));
+            } else if earlier.iter().any(|w| w.name == world.name) {
+                err(format_args!("worlds.{}: there is already a world named '{}'", i, world.name));
+            }
+            if let Some(prefix) = &world.prefix {
+                // A numeric prefix would be mistaken for the version in `/<ver>/<focus>`.
+                if !is_valid(prefix)
+                    || prefix.chars().all(|c| c.is_ascii_digit())
+                    || RESERVED_PREFIXES.contains(&prefix.as_str())
+                {
+                    err(format_args!("worlds.{}: invalid prefix '{}'", i, prefix));
+                } else if earlier.iter().any(|w| w.prefix == world.prefix) {
+                    err(format_args!("worlds.{}: prefix '{}' is already used", i, prefix));
                 }
             }
-        } else {
-            log.err("impossible - config file has no parent dir");
+            if let Some(host) = &world.host {
+                if earlier.iter().any(|w| w.host == world.host) {
+                    err(format_args!("worlds.{}: host '{}' is already used", i, host));
+                }
+            }
+            if world.prefix.is_none() && world.host.is_none() {
+                err(format_args!("worlds.{}: a prefix or host is required", i));
+            }
         }
-        Some(self_)
+        ok
+    }
+
+    /// Return the settings of the main world.
+    pub fn main_world(&self) -> World<'_> {
+        World {
+            name: MAIN_WORLD,
+            prefix: None,
+            host: None,
+            render: &self.paths.render,
+            sim: &self.paths.sim,
+            state: &self.paths.state,
+            runtime: &self.runtime,
+        }
+    }
+
+    /// Return the settings of every world, starting with the main one.
+    pub fn worlds(&self) -> impl Iterator<Item = World<'_>> {
+        std::iter::once(self.main_world()).chain(self.worlds.iter().map(World::from))
+    }
+
+    /// Return the settings of the world with the given name, if there is one.
+    pub fn world(&self, name: &str) -> Option<World<'_>> {
+        self.worlds().find(|world| world.name == name)
+    }
+
+    /// Apply the runtime settings that were previously changed through the admin panel.
+    fn load_runtime(&self, log: &Log) {
+        let path = &self.paths.runtime;
+        let contents = match fs::read(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                log.err(format_args!("could not read '{}': {}", path.display(), e));
+                return
+            }
+        };
+        let file = match toml::from_slice::<RuntimeFile>(&contents) {
+            Ok(file) => file,
+            Err(e) => {
+                log.err(format_args!("while parsing '{}': {}", path.display(), e));
+                return
+            }
+        };
+        if file.main != RuntimeValues::default() || !file.worlds.is_empty() {
+            log.info(format_args!("applying runtime settings from '{}'", path.display()));
+        }
+        self.runtime.apply(file.main);
+        for (name, overrides) in file.worlds {
+            match self.worlds.iter().find(|world| world.name == name) {
+                Some(world) => world.runtime.apply(overrides),
+                None => log.err(format_args!(
+                    "'{}' has settings for unknown world '{}'",
+                    path.display(),
+                    name,
+                )),
+            }
+        }
+    }
+
+    /// List every field of the config and its value, in TOML syntax.
+    fn fields(&self, log: &Log) -> Vec<(String, String)> {
+        let mut fields = Vec::new();
+        match toml::Value::try_from(self) {
+            Ok(cfg) => flatten(String::new(), &cfg, &mut fields),
+            Err(e) => log.err(format_args!("could not serialize config: {}", e)),
+        }
+        fields
+    }
+
+    /// List every field of the effective config, along with where its value came from.
+    pub fn effective(&self, log: &Log) -> Vec<Entry> {
+        let fields = self.fields(log);
+        let mut overridden = Vec::new();
+        let runtimes = std::iter::once((String::from("runtime"), &self.runtime))
+            .chain(self.worlds.iter().enumerate()
+                .map(|(i, world)| (format!("worlds.{}.runtime", i), &world.runtime)));
+        for (prefix, runtime) in runtimes {
+            match toml::Value::try_from(runtime.overrides()) {
+                Ok(overrides) => flatten(prefix, &overrides, &mut overridden),
+                Err(e) => log.err(format_args!("could not serialize runtime settings: {}", e)),
+            }
+        }
+
+        fields.into_iter()
+            .map(|(key, value)| {
+                let source = if overridden.iter().any(|(k, _)| *k == key) {
+                    self.paths.runtime.display().to_string()
+                } else {
+                    self.sources.get(&key).cloned().unwrap_or_else(|| String::from("default"))
+                };
+                Entry { key, value, source }
+            })
+            .collect()
+    }
+
+    /// List the fields whose values differ in another config.
+    pub fn changes(&self, new: &Self, log: &Log) -> Vec<Change> {
+        let old = self.fields(log).into_iter().collect::<HashMap<_, _>>();
+        let new = new.fields(log).into_iter().collect::<HashMap<_, _>>();
+        let mut keys = old.keys().chain(new.keys()).collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys.into_iter()
+            .filter(|key| old.get(*key) != new.get(*key))
+            .map(|key| Change {
+                key: key.clone(),
+                old: old.get(key).cloned(),
+                new: new.get(key).cloned(),
+            })
+            .collect()
+    }
+
+    /// Record the runtime settings that differ from the config file,
+    /// so that they are re-applied when the server restarts.
+    pub fn save_runtime(&self, log: &Log) {
+        let path = &self.paths.runtime;
+        let file = RuntimeFile {
+            main: self.runtime.overrides(),
+            worlds: self.worlds.iter()
+                .map(|world| (world.name.clone(), world.runtime.overrides()))
+                .filter(|(_, overrides)| *overrides != RuntimeValues::default())
+                .collect(),
+        };
+        let toml = match toml::to_string(&file) {
+            Ok(toml) => toml,
+            Err(e) => {
+                log.err(format_args!("could not serialize runtime settings: {}", e));
+                return
+            }
+        };
+        if let Err(e) = fs::write(path, toml) {
+            log.err(format_args!("could not write runtime settings to '{}': {}", path.display(), e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a CIDR range that is expected to be valid.
+    fn cidr(s: &str) -> Cidr {
+        Cidr::try_from(s.to_string()).unwrap()
+    }
+
+    /// Parse an IP address.
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_prefixes() {
+        let range = cidr("10.1.0.0/16");
+        assert!(range.contains(ip("10.1.0.0")));
+        assert!(range.contains(ip("10.1.255.255")));
+        assert!(!range.contains(ip("10.2.0.0")));
+        assert!(!range.contains(ip("9.255.255.255")));
+        // Only the leading bits of the range's address matter.
+        assert!(cidr("192.168.1.77/24").contains(ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn ipv6_prefixes() {
+        let range = cidr("2001:db8::/32");
+        assert!(range.contains(ip("2001:db8::1")));
+        assert!(range.contains(ip("2001:db8:ffff:ffff::")));
+        assert!(!range.contains(ip("2001:db9::")));
+    }
+
+    #[test]
+    fn whole_and_single_address_ranges() {
+        assert!(cidr("0.0.0.0/0").contains(ip("1.2.3.4")));
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+
+        assert!(cidr("127.0.0.1/32").contains(ip("127.0.0.1")));
+        assert!(!cidr("127.0.0.1/32").contains(ip("127.0.0.2")));
+        assert!(cidr("::1/128").contains(ip("::1")));
+        assert!(!cidr("::1/128").contains(ip("::2")));
+
+        // Without a prefix length, the range is a single address.
+        assert_eq!(String::from(cidr("127.0.0.1")), "127.0.0.1/32");
+        assert_eq!(String::from(cidr("::1")), "::1/128");
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        let invalid = [
+            "10.0.0.0/33", "::/129", "10.0.0.0/-1", "10.0.0.0/", "10.0.0.0/x", "10.0.0/8", "",
+        ];
+        for s in &invalid {
+            assert!(Cidr::try_from(s.to_string()).is_err(), "'{}' should be invalid", s);
+        }
+    }
+
+    #[test]
+    fn mixed_address_families() {
+        // IPv4 addresses never fall in IPv6 ranges, even ones that cover everything.
+        assert!(!cidr("::/0").contains(ip("10.0.0.1")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        // IPv4 addresses mapped into IPv6 are treated as IPv4.
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+    }
+
+    /// Create a log that records errors without printing them.
+    fn quiet_log() -> Log {
+        let log = Log::new();
+        log.configure(&LogCfg { stderr_level: Level::Off, ..LogCfg::default() });
+        log
+    }
+
+    /// Return the bodies of the error messages in a log, oldest first.
+    fn errors(log: &Log) -> Vec<String> {
+        let mut errors = Vec::new();
+        log.for_each(|msg| if msg.kind == MessageKind::Error { errors.push(msg.body.clone()) });
+        errors.reverse();
+        errors
+    }
+
+    /// Load the example config, overridden by the given environment variables.
+    fn load_with_env(log: &Log, vars: &[(&str, &str)]) -> Option<Cfg> {
+        let table = toml::from_str(include_str!("../../../example/Config.toml")).unwrap();
+        let vars = vars.iter().map(|(var, value)| (var.to_string(), value.to_string()));
+        Cfg::from_table(log, table, vars.collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn env_var_names() {
+        let field = |var| env_field(var).map(|path| path.join("."));
+        assert_eq!(field("NOKEVAIR_ADDR").as_deref(), Some("addr"));
+        assert_eq!(field("NOKEVAIR_RUNTIME_SIM_RATE").as_deref(), Some("runtime.sim-rate"));
+        assert_eq!(field("NOKEVAIR_PATHS_SALT_KEY").as_deref(), Some("paths.salt-key"));
+        assert_eq!(field("NOKEVAIR_NETWORK_ADMIN_ADDR").as_deref(), Some("network.admin-addr"));
+        assert_eq!(field("NOKEVAIR_NETWORK_ALLOW_ADMIN").as_deref(), Some("network.allow.admin"));
+        assert_eq!(field("NOKEVAIR_BOGUS"), None);
+        assert_eq!(field("NOKEVAIR_WORLDS_0_NAME"), None);
+        assert_eq!(field("OTHER_ADDR"), None);
+    }
+
+    #[test]
+    fn env_value_types() {
+        let string = |s: &str| toml::Value::String(s.to_string());
+        assert_eq!(parse_env_value(None, "true"), Ok(toml::Value::Boolean(true)));
+        let int = toml::Value::Integer(15);
+        assert_eq!(parse_env_value(Some(&int), "3"), Ok(toml::Value::Integer(3)));
+        assert!(parse_env_value(Some(&int), "fast").is_err());
+        // Fields that are strings in the config file (like paths) are never parsed.
+        assert_eq!(parse_env_value(Some(&string("0.lua")), "1.lua"), Ok(string("1.lua")));
+        assert_eq!(parse_env_value(Some(&string("0.lua")), "42"), Ok(string("42")));
+        // Other values fall back to strings if they aren't valid TOML.
+        assert_eq!(parse_env_value(None, "state/other"), Ok(string("state/other")));
+        assert_eq!(
+            parse_env_value(None, r#"["127.0.0.1/32", "::1"]"#),
+            Ok(toml::Value::Array(vec![string("127.0.0.1/32"), string("::1")])),
+        );
+    }
+
+    #[test]
+    fn env_overrides() {
+        let log = quiet_log();
+        let cfg = load_with_env(&log, &[
+            ("NOKEVAIR_ADDR", "0.0.0.0:8080"),
+            ("NOKEVAIR_RUNTIME_SIM_RATE", "3"),
+            ("NOKEVAIR_PATHS_STATE", "other-state"),
+            ("NOKEVAIR_NETWORK_ALLOW_ADMIN", r#"["127.0.0.1/32"]"#),
+            ("HOME", "/root"),
+        ]).unwrap();
+        assert_eq!(errors(&log), Vec::<String>::new());
+        assert_eq!(cfg.addr, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(cfg.runtime.sim_rate.load(Ordering::Relaxed), 3);
+        assert_eq!(cfg.paths.state, PathBuf::from("other-state"));
+        let admin = cfg.network.allow.admin.as_ref().unwrap();
+        assert!(admin.len() == 1 && admin[0].contains("127.0.0.1".parse().unwrap()));
+        assert_eq!(cfg.sources["runtime.sim-rate"], "env NOKEVAIR_RUNTIME_SIM_RATE");
+        assert_eq!(cfg.sources["runtime.template-refresh"], "config file");
+    }
+
+    #[test]
+    fn unknown_env_vars() {
+        let log = quiet_log();
+        let cfg = load_with_env(&log, &[
+            ("NOKEVAIR_RUNTIME_SIM_RAT", "3"),
+            ("NOKEVAIR_BOGUS", "1"),
+        ]);
+        assert!(cfg.is_some());
+        assert_eq!(errors(&log), [
+            "unrecognized environment variable 'NOKEVAIR_BOGUS'",
+            "unrecognized environment variable 'NOKEVAIR_RUNTIME_SIM_RAT'",
+        ]);
+    }
+
+    #[test]
+    fn invalid_env_values() {
+        let log = quiet_log();
+        assert!(load_with_env(&log, &[("NOKEVAIR_RUNTIME_SIM_RATE", "fast")]).is_none());
+        assert_eq!(errors(&log).len(), 1);
+        assert!(errors(&log)[0].starts_with("while applying 'NOKEVAIR_RUNTIME_SIM_RATE'"));
+
+        let log = quiet_log();
+        assert!(load_with_env(&log, &[("NOKEVAIR_ADDR", "nowhere")]).is_none());
+        assert!(errors(&log)[0].starts_with("while parsing config file"));
     }
 }
@@ -1,13 +1,22 @@
 //! Utilities for error/info logging.
 
-use chrono::{DateTime, Utc};
-use serde::{Serialize, Serializer};
+use chrono::{DateTime, Utc, TimeZone as _};
+use regex::Regex;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use tokio::sync::broadcast;
 
+use std::collections::VecDeque;
+use std::ffi::OsString;
 use std::fmt::Display;
-use std::sync::{RwLock, PoisonError};
+use std::fs::{self, File, OpenOptions};
+use std::io::{IsTerminal as _, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock, PoisonError};
+
+use super::cfg::{Level, LogCfg, StderrFormat};
 
 /// Represents the type of a log message.
-#[derive(Clone, Copy, Serialize)]
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MessageKind {
     /// An error message.
     #[serde(rename="error")]
@@ -23,9 +32,111 @@ pub enum MessageKind {
     Lua,
 }
 
+impl MessageKind {
+    /// The name of this kind of message, which labels it on stderr.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Info => "info",
+            Self::Status => "status",
+            Self::Lua => "lua",
+        }
+    }
+
+    /// The ANSI escape sequence that sets the colour of the label.
+    fn color(self) -> &'static str {
+        match self {
+            Self::Error => "\x1b[1;31m",
+            Self::Info => "\x1b[1;33m",
+            Self::Status => "\x1b[1;32m",
+            Self::Lua => "\x1b[1;36m",
+        }
+    }
+}
+
+/// Identifies the part of the server that a message came from.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Subsystem {
+    /// The simulation thread.
+    Sim,
+    /// The renderer and the focus functions it uses.
+    Render,
+    /// Handling of HTTP requests in general.
+    Http,
+    /// Logging in, sessions and API tokens.
+    Auth,
+    /// Loading and rendering Tera templates.
+    Templates,
+}
+
+tokio::task_local! {
+    /// The ID of the HTTP request being handled by the current task, if any.
+    /// Messages logged while it is set are tagged with it automatically.
+    pub static REQUEST_ID: u64;
+}
+
+/// Structured data that can be attached to a message.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Fields {
+    /// The part of the server that the message came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subsystem: Option<Subsystem>,
+    /// The world involved, unless it is the main one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub world: Option<String>,
+    /// The version of the world state involved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<usize>,
+    /// The name of the focus involved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focus: Option<String>,
+    /// The simulation file involved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sim_file: Option<String>,
+    /// The ID of the HTTP request that caused the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<u64>,
+}
+
+impl Fields {
+    /// Create a set of fields describing a particular subsystem.
+    pub fn new(subsystem: Subsystem) -> Self {
+        Self { subsystem: Some(subsystem), ..Self::default() }
+    }
+
+    /// Attach the name of a world.
+    pub fn world<S: Into<String>>(mut self, world: S) -> Self {
+        self.world = Some(world.into());
+        self
+    }
+
+    /// Attach a version of the world state.
+    pub fn version(mut self, version: usize) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Attach the name of a focus.
+    pub fn focus<S: Into<String>>(mut self, focus: S) -> Self {
+        self.focus = Some(focus.into());
+        self
+    }
+
+    /// Attach the name of a simulation file.
+    pub fn sim_file<S: Into<String>>(mut self, sim_file: S) -> Self {
+        self.sim_file = Some(sim_file.into());
+        self
+    }
+}
+
 /// Represents a message in the log.
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Message {
+    /// The ID of the message. IDs increase monotonically, so they stay
+    /// valid after older messages are evicted.
+    #[serde(default)]
+    pub id: u64,
     /// What kind of message is this?
     pub kind: MessageKind,
     /// Has the admin already marked this message as deleted?
@@ -33,8 +144,33 @@ pub struct Message {
     /// The content of the message.
     pub body: String,
     /// The time when the message was created.
-    #[serde(serialize_with = "format_time")]
+    #[serde(serialize_with = "format_time", deserialize_with = "parse_time")]
     pub time: DateTime<Utc>,
+    /// How many times has this message been logged? Duplicates that are
+    /// logged soon after each other are collapsed into a single message.
+    #[serde(default = "one", skip_serializing_if = "is_one")]
+    pub count: u64,
+    /// The time when the most recent duplicate of this message was logged.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "format_opt_time",
+        deserialize_with = "parse_opt_time",
+    )]
+    pub last_time: Option<DateTime<Utc>>,
+    /// Structured data describing where the message came from.
+    #[serde(flatten)]
+    pub fields: Fields,
+}
+
+/// Used as the default number of occurrences of a message.
+fn one() -> u64 {
+    1
+}
+
+/// Check whether a message only occurred once.
+fn is_one(count: &u64) -> bool {
+    *count == 1
 }
 
 /// Deserialize a `DateTime` by representing it with a timestamp.
@@ -42,22 +178,351 @@ fn format_time<S: Serializer>(time: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Er
     s.serialize_i64(time.timestamp())
 }
 
+/// Deserialize a `DateTime` that was represented with a timestamp.
+fn parse_time<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<Utc>, D::Error> {
+    let timestamp = i64::deserialize(d)?;
+    Ok(Utc.timestamp(timestamp, 0))
+}
+
+/// Serialize an optional `DateTime` by representing it with a timestamp.
+fn format_opt_time<S: Serializer>(time: &Option<DateTime<Utc>>, s: S) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => format_time(time, s),
+        None => s.serialize_none(),
+    }
+}
+
+/// Deserialize an optional `DateTime` that was represented with a timestamp.
+fn parse_opt_time<'de, D: Deserializer<'de>>(d: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    let timestamp = Option::<i64>::deserialize(d)?;
+    Ok(timestamp.map(|t| Utc.timestamp(t, 0)))
+}
+
 impl Message {
     /// Create a new message with specified content.
-    pub fn new(kind: MessageKind, body: String) -> Self {
+    pub fn new(kind: MessageKind, body: String, fields: Fields) -> Self {
         Self {
+            id: 0,
             kind,
             is_deleted: false,
             body,
             time: Utc::now(),
+            count: 1,
+            last_time: None,
+            fields,
+        }
+    }
+
+    /// Check whether another message should be collapsed into this one.
+    fn is_duplicate(&self, other: &Self) -> bool {
+        !self.is_deleted
+            && self.kind == other.kind
+            && self.fields.subsystem == other.fields.subsystem
+            && self.body == other.body
+    }
+
+    /// Estimate how many bytes of memory the message occupies.
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.body.len()
+    }
+}
+
+/// A ring buffer of messages that evicts the oldest ones once it grows too large.
+struct Messages {
+    /// The messages, stored in the order they were created.
+    list: VecDeque<Message>,
+    /// The ID that will be given to the next message.
+    next_id: u64,
+    /// The estimated number of bytes occupied by the messages.
+    bytes: usize,
+    /// The maximum number of messages that are kept.
+    max_messages: usize,
+    /// The maximum number of bytes that the messages may occupy.
+    max_bytes: usize,
+    /// For how many seconds after a message is logged are duplicates collapsed into it?
+    dedup_window: i64,
+    /// When the most recent error messages were logged, including duplicates.
+    error_times: VecDeque<DateTime<Utc>>,
+    /// The least important kind of message that is kept.
+    level: Level,
+}
+
+/// The maximum number of error times that are remembered.
+const ERROR_HISTORY: usize = 10_000;
+
+/// The maximum number of recent messages that are checked for duplicates.
+const DEDUP_SCAN: usize = 100;
+
+impl Default for Messages {
+    fn default() -> Self {
+        let cfg = LogCfg::default();
+        Self {
+            list: VecDeque::new(),
+            next_id: 0,
+            bytes: 0,
+            max_messages: cfg.max_messages,
+            max_bytes: cfg.max_bytes,
+            dedup_window: cfg.dedup_window as i64,
+            error_times: VecDeque::new(),
+            level: cfg.memory_level,
         }
     }
 }
 
+impl Messages {
+    /// Add a message to the end of the buffer, evicting old messages if necessary.
+    fn push(&mut self, msg: Message) {
+        self.bytes += msg.size();
+        self.list.push_back(msg);
+        self.evict();
+    }
+
+    /// Remove the oldest messages until the buffer is within its limits.
+    fn evict(&mut self) {
+        while self.list.len() > self.max_messages || self.bytes > self.max_bytes {
+            match self.list.pop_front() {
+                Some(msg) => self.bytes -= msg.size(),
+                None => break,
+            }
+        }
+    }
+
+    /// Find a recent message that a new message should be collapsed into.
+    /// Collapsing updates the time of older messages, so the list isn't
+    /// ordered by it, and every scanned message has to be checked.
+    fn find_duplicate(&mut self, msg: &Message) -> Option<&mut Message> {
+        let window = self.dedup_window;
+        self.list.iter_mut()
+            .rev()
+            .take(DEDUP_SCAN)
+            .filter(|prev| {
+                (msg.time - prev.last_time.unwrap_or(prev.time)).num_seconds() < window
+            })
+            .find(|prev| prev.is_duplicate(msg))
+    }
+
+    /// Find the message with a given ID, if it has not been evicted.
+    fn get_mut(&mut self, id: u64) -> Option<&mut Message> {
+        let idx = self.list.binary_search_by_key(&id, |msg| msg.id).ok()?;
+        self.list.get_mut(idx)
+    }
+}
+
+/// Prints messages to stderr.
+struct Stderr {
+    /// The least important kind of message that is printed.
+    level: Level,
+    /// How messages are printed. This is never `StderrFormat::Auto`.
+    format: StderrFormat,
+}
+
+impl Stderr {
+    /// Create the sink described by the config, checking
+    /// whether stderr is a terminal if necessary.
+    fn new(cfg: &LogCfg) -> Self {
+        let format = match cfg.stderr_format {
+            StderrFormat::Auto if std::io::stderr().is_terminal() => StderrFormat::Color,
+            StderrFormat::Auto => StderrFormat::Plain,
+            format => format,
+        };
+        Self { level: cfg.stderr_level, format }
+    }
+
+    /// Print a message, if its kind is included.
+    fn print(&self, msg: &Message) {
+        if !self.level.permits(msg.kind) {
+            return
+        }
+        let count = if msg.count == 1 {
+            String::new()
+        } else {
+            format!(" (\u{d7}{})", msg.count)
+        };
+        match self.format {
+            StderrFormat::Json => match serde_json::to_string(msg) {
+                Ok(line) => eprintln!("{}", line),
+                Err(e) => eprintln!("error: could not serialize message: {}", e),
+            },
+            StderrFormat::Color => eprintln!(
+                "{}{}: \x1b[39;49m{}{}",
+                msg.kind.color(),
+                msg.kind.name(),
+                msg.body,
+                count,
+            ),
+            _ => eprintln!("{}: {}{}", msg.kind.name(), msg.body, count),
+        }
+    }
+}
+
+/// How many messages can be queued for a subscriber before it starts missing them.
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+/// Tracks the file that messages are persisted to.
+struct LogFile {
+    /// The path of the current file. Rotated files have `.1`, `.2`, etc. appended.
+    path: PathBuf,
+    /// The open handle to the current file.
+    file: File,
+    /// The size of the current file in bytes.
+    size: u64,
+    /// When was the first message written to the current file?
+    opened: DateTime<Utc>,
+    /// After how many bytes is the file rotated?
+    max_size: u64,
+    /// After how many seconds is the file rotated?
+    max_age: i64,
+    /// How many rotated files are kept?
+    retain: u32,
+    /// The least important kind of message that is written.
+    level: Level,
+    /// Messages that duplicates have been collapsed into since they were written,
+    /// which are written again with their final count once no more duplicates
+    /// can be collapsed into them.
+    pending: Vec<Message>,
+}
+
+/// Return the path of the `n`th most recently rotated log file,
+/// where the current file is the 0th.
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+/// Read the last `n` messages stored in the current and rotated log files,
+/// in the order they were created. If a message was written more than once
+/// (because duplicates were collapsed into it), only the latest copy is kept.
+fn read_tail(path: &Path, retain: u32, n: usize) -> std::io::Result<Vec<Message>> {
+    let mut tail = Vec::<Message>::new();
+    let mut seen = std::collections::HashSet::new();
+    for i in 0..=retain {
+        let contents = match fs::read_to_string(rotated_path(path, i)) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for line in contents.lines().rev() {
+            if tail.len() >= n {
+                break;
+            }
+            if let Ok(msg) = serde_json::from_str::<Message>(line) {
+                if seen.insert(msg.id) {
+                    tail.push(msg);
+                }
+            }
+        }
+    }
+    tail.sort_by_key(|msg| msg.id);
+    Ok(tail)
+}
+
+impl LogFile {
+    /// Open the log file for appending, creating it if necessary.
+    fn open(cfg: &LogCfg, path: PathBuf) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        // The age of the file is measured from its first message.
+        let opened = fs::read_to_string(&path)?
+            .lines()
+            .next()
+            .and_then(|line| serde_json::from_str::<Message>(line).ok())
+            .map_or_else(Utc::now, |msg| msg.time);
+        Ok(Self {
+            path,
+            file,
+            size,
+            opened,
+            max_size: cfg.max_size,
+            max_age: cfg.max_age as i64,
+            retain: cfg.retain,
+            level: cfg.file_level,
+            pending: Vec::new(),
+        })
+    }
+
+    /// If the current file is too large or too old, move it aside (along with
+    /// the files that were previously rotated) and start a new one.
+    fn rotate_if_needed(&mut self) -> std::io::Result<()> {
+        let too_large = self.size >= self.max_size;
+        let too_old = (Utc::now() - self.opened).num_seconds() >= self.max_age;
+        if self.size == 0 || !(too_large || too_old) {
+            return Ok(());
+        }
+
+        // If no rotated files are kept, the oldest one is the current file.
+        let oldest = rotated_path(&self.path, self.retain);
+        if oldest.exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for i in (0..self.retain).rev() {
+            let from = rotated_path(&self.path, i);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, i + 1))?;
+            }
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened = Utc::now();
+        Ok(())
+    }
+
+    /// Append a message to the file, rotating it first if necessary.
+    /// Messages whose kind isn't included are skipped.
+    fn write(&mut self, msg: &Message) -> std::io::Result<()> {
+        if !self.level.permits(msg.kind) {
+            return Ok(());
+        }
+        self.rotate_if_needed()?;
+        let mut line = serde_json::to_string(msg)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Record that a duplicate was collapsed into a message that was already
+    /// written, so that the message is written again once its burst is over.
+    fn defer(&mut self, msg: &Message) {
+        if !self.level.permits(msg.kind) {
+            return;
+        }
+        match self.pending.iter_mut().find(|pending| pending.id == msg.id) {
+            Some(pending) => *pending = msg.clone(),
+            None => self.pending.push(msg.clone()),
+        }
+    }
+
+    /// Write the deferred messages whose last duplicate was logged at least
+    /// `window` seconds before `now`, so that no more can be collapsed into them.
+    fn write_closed(&mut self, now: DateTime<Utc>, window: i64) -> std::io::Result<()> {
+        let (closed, open) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|msg| {
+                (now - msg.last_time.unwrap_or(msg.time)).num_seconds() >= window
+            });
+        self.pending = open;
+        for msg in closed {
+            self.write(&msg)?;
+        }
+        Ok(())
+    }
+}
+
 /// Keeps track of all messages written to the log.
 pub struct Log {
-    /// The list of messages, stored in the order they were created.
-    messages: RwLock<Vec<Message>>,
+    /// The most recent messages, stored in the order they were created.
+    messages: RwLock<Messages>,
+    /// The file that messages are persisted to, if there is one.
+    file: Mutex<Option<LogFile>>,
+    /// Sends each new message to anyone watching the log live.
+    sender: broadcast::Sender<Message>,
+    /// How messages are printed to stderr.
+    stderr: RwLock<Stderr>,
 }
 
 impl Log {
@@ -65,100 +530,540 @@ impl Log {
     pub fn new() -> Self {
         Self {
             messages: RwLock::default(),
+            file: Mutex::default(),
+            sender: broadcast::channel(SUBSCRIBER_CAPACITY).0,
+            stderr: RwLock::new(Stderr::new(&LogCfg::default())),
+        }
+    }
+
+    /// Apply the settings from the `[log]` section of the config file.
+    pub fn configure(&self, cfg: &LogCfg) {
+        *self.stderr.write().unwrap_or_else(PoisonError::into_inner) = Stderr::new(cfg);
+        {
+            let mut messages = self.messages.write()
+                .unwrap_or_else(PoisonError::into_inner);
+            messages.max_messages = cfg.max_messages;
+            messages.max_bytes = cfg.max_bytes;
+            messages.dedup_window = cfg.dedup_window as i64;
+            messages.level = cfg.memory_level;
+            messages.list.retain(|msg| cfg.memory_level.permits(msg.kind));
+            messages.bytes = messages.list.iter().map(Message::size).sum();
+            messages.evict();
+        }
+    }
+
+    /// Start persisting messages to the file specified in the `[log]`
+    /// section of the config file, if there is one.
+    pub fn persist(&self, cfg: &LogCfg) {
+        if let Some(path) = &cfg.file {
+            self.attach_file(cfg, path.clone());
         }
     }
 
-    /// Push a message to the log.
-    fn add_message(&self, msg: Message) {
-        self.messages.write()
+    /// Start persisting messages to a file.
+    /// Messages from previous runs of the server are reloaded from it, and
+    /// messages that have already been logged during this run are written to it.
+    fn attach_file(&self, cfg: &LogCfg, path: PathBuf) {
+        let tail = match read_tail(&path, cfg.retain, cfg.reload) {
+            Ok(tail) => tail,
+            Err(e) => {
+                self.err(format_args!("could not reload log from '{}': {}", path.display(), e));
+                Vec::new()
+            }
+        };
+
+        let mut file = match LogFile::open(cfg, path.clone()) {
+            Ok(file) => file,
+            Err(e) => {
+                self.err(format_args!("could not open log file '{}': {}", path.display(), e));
+                return
+            }
+        };
+
+        let mut messages = self.messages.write()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Messages from this run are numbered after the ones that were reloaded.
+        let mut next_id = tail.last().map_or(0, |msg| msg.id + 1);
+        let current = std::mem::take(&mut messages.list);
+        messages.bytes = 0;
+        for msg in tail {
+            if messages.level.permits(msg.kind) {
+                messages.push(msg);
+            }
+        }
+        for mut msg in current {
+            msg.id = next_id;
+            next_id += 1;
+            if let Err(e) = file.write(&msg) {
+                self.report_write_error(e);
+            }
+            messages.push(msg);
+        }
+        messages.next_id = next_id;
+        *self.file.lock().unwrap_or_else(PoisonError::into_inner) = Some(file);
+    }
+
+    /// Push a message to the log, print it, write it to the log file if there
+    /// is one, and send it to subscribers. Each of these only happens if the
+    /// level configured for it includes the message; messages that aren't
+    /// kept in memory aren't sent to subscribers either.
+    ///
+    /// If it duplicates a recent message, it is collapsed into that message
+    /// instead. The updated message is sent every time, so that subscribers see
+    /// the latest count, but it is only printed when its count reaches a power
+    /// of two, so that floods of duplicates don't drown out the console. It is
+    /// written to the log file once more when the burst of duplicates is over
+    /// (see `write_closed_duplicates`).
+    fn add_message(&self, mut msg: Message) {
+        if msg.fields.request_id.is_none() {
+            msg.fields.request_id = REQUEST_ID.try_with(|id| *id).ok();
+        }
+        let mut messages = self.messages.write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let MessageKind::Error = msg.kind {
+            if messages.error_times.len() >= ERROR_HISTORY {
+                messages.error_times.pop_front();
+            }
+            messages.error_times.push_back(msg.time);
+        }
+        let keep = messages.level.permits(msg.kind);
+        let duplicate = if keep { messages.find_duplicate(&msg) } else { None };
+        let is_duplicate = duplicate.is_some();
+        let msg = match duplicate {
+            Some(prev) => {
+                prev.count += 1;
+                prev.last_time = Some(msg.time);
+                prev.clone()
+            }
+            None => {
+                msg.id = messages.next_id;
+                messages.next_id += 1;
+                if keep {
+                    messages.push(msg.clone());
+                }
+                msg
+            }
+        };
+        drop(messages);
+
+        if msg.count.is_power_of_two() {
+            self.stderr.read().unwrap_or_else(PoisonError::into_inner).print(&msg);
+        }
+        if let Some(file) = &mut *self.file.lock().unwrap_or_else(PoisonError::into_inner) {
+            if is_duplicate {
+                file.defer(&msg);
+            } else if let Err(e) = file.write(&msg) {
+                self.report_write_error(e);
+            }
+        }
+        if keep && self.sender.receiver_count() > 0 {
+            // This only fails if every subscriber has gone away in the meantime.
+            let _ = self.sender.send(msg);
+        }
+    }
+
+    /// Write the messages whose bursts of duplicates are over to the log file
+    /// with their final count. This should be called regularly.
+    pub fn write_closed_duplicates(&self) {
+        let window = self.messages.read()
             .unwrap_or_else(PoisonError::into_inner)
-            .push(msg);
+            .dedup_window;
+        if let Some(file) = &mut *self.file.lock().unwrap_or_else(PoisonError::into_inner) {
+            if let Err(e) = file.write_closed(Utc::now(), window) {
+                self.report_write_error(e);
+            }
+        }
+    }
+
+    /// Print an error that occurred while writing to the log file. This isn't
+    /// added to the log, since that would recurse.
+    fn report_write_error(&self, e: std::io::Error) {
+        let msg = Message::new(
+            MessageKind::Error,
+            format!("could not write to log file: {}", e),
+            Fields::default(),
+        );
+        self.stderr.read().unwrap_or_else(PoisonError::into_inner).print(&msg);
+    }
+
+    /// Return how many error messages (including duplicates) have been logged since a given time.
+    pub fn count_errors_since(&self, since: DateTime<Utc>) -> u64 {
+        let messages = self.messages.read()
+            .unwrap_or_else(PoisonError::into_inner);
+        messages.error_times.iter().rev().take_while(|&&time| time >= since).count() as u64
+    }
+
+    /// Return how many error messages (including duplicates, up to
+    /// `ERROR_HISTORY`) have been logged since the log was created.
+    pub fn count_errors(&self) -> u64 {
+        let messages = self.messages.read()
+            .unwrap_or_else(PoisonError::into_inner);
+        messages.error_times.len() as u64
+    }
+
+    /// Receive each message as it is added to the log.
+    pub fn subscribe(&self) -> broadcast::Receiver<Message> {
+        self.sender.subscribe()
+    }
+
+    /// Return a handle that logs messages with some structured fields attached.
+    pub fn with(&self, fields: Fields) -> Scoped<'_> {
+        Scoped { log: self, fields }
+    }
+
+    /// Return a handle that logs messages from a particular subsystem.
+    pub fn of(&self, subsystem: Subsystem) -> Scoped<'_> {
+        self.with(Fields::new(subsystem))
     }
 
     /// Add an error message to the log.
     pub fn err<M: Display>(&self, msg: M) {
-        let body = format!("{}", msg);
-        eprintln!("\x1b[1;31merror: \x1b[39;49m{}", body);
-        self.add_message(Message::new(MessageKind::Error, body));
+        self.with(Fields::default()).err(msg)
     }
 
     /// Add an info message to the log.
     pub fn info<M: Display>(&self, msg: M) {
-        let body = format!("{}", msg);
-        eprintln!("\x1b[1;33minfo: \x1b[39;49m{}", body);
-        self.add_message(Message::new(MessageKind::Info, body));
+        self.with(Fields::default()).info(msg)
     }
 
-    /// Add a status message to the log.
-    pub fn status<M: Display>(&self, msg: M) {
-        let body = format!("{}", msg);
-        eprintln!("\x1b[1;32mstatus: \x1b[39;49m{}", body);
-        self.add_message(Message::new(MessageKind::Status, body));
-    }
-    
     /// Add a Lua message to the log.
     pub fn lua<M: Display>(&self, msg: M) {
-        let body = format!("{}", msg);
-        eprintln!("\x1b[1;36mlua: \x1b[39;49m{}", body);
-        self.add_message(Message::new(MessageKind::Lua, body));
+        self.with(Fields::default()).lua(msg)
     }
 
     /// Call a function on each message in order opposite to when they were created.
-    pub fn for_each<F: FnMut(usize, &Message)>(&self, mut f: F) {
+    pub fn for_each<F: FnMut(&Message)>(&self, mut f: F) {
         let messages = self.messages.read()
             .unwrap_or_else(PoisonError::into_inner);
-        for (idx, msg) in messages.iter().enumerate().rev() {
-            f(idx, msg);
+        for msg in messages.list.iter().rev() {
+            f(msg);
         }
     }
 
+    /// Return the newest messages permitted by a filter, newest first, up to its
+    /// limit. If there are more, also return the `before` value that fetches them.
+    pub fn query(&self, filter: &Filter) -> (Vec<Message>, Option<u64>) {
+        let mut messages = Vec::new();
+        let mut is_truncated = false;
+        self.for_each(|msg| {
+            if filter.permits(msg) {
+                if messages.len() < filter.limit() {
+                    messages.push(msg.clone());
+                } else {
+                    is_truncated = true;
+                }
+            }
+        });
+        let before = messages.last().map(|msg: &Message| msg.id).filter(|_| is_truncated);
+        (messages, before)
+    }
+
     /// Delete a message in the log, or undelete it if it is already deleted.
-    pub fn toggle_deleted(&self, idx: usize) {
+    /// Return whether the message is now deleted, or `None` if it does not exist.
+    pub fn toggle_deleted(&self, id: u64) -> Option<bool> {
         let mut messages = self.messages.write()
             .unwrap_or_else(PoisonError::into_inner);
-        if let Some(msg) = messages.get_mut(idx) {
+        if let Some(msg) = messages.get_mut(id) {
             msg.is_deleted = !msg.is_deleted;
+            Some(msg.is_deleted)
         } else {
             std::mem::drop(messages); // don't deadlock
-            self.err("attempted to delete non-existent message")
+            self.err("attempted to delete non-existent message");
+            None
         }
     }
 }
 
+/// A handle for adding messages with structured fields to the log.
+pub struct Scoped<'a> {
+    /// The log that messages are added to.
+    log: &'a Log,
+    /// The fields attached to each message.
+    fields: Fields,
+}
+
+impl Scoped<'_> {
+    /// Add a message of a particular kind to the log.
+    fn add(&self, kind: MessageKind, body: String) {
+        self.log.add_message(Message::new(kind, body, self.fields.clone()));
+    }
+
+    /// Add an error message to the log.
+    pub fn err<M: Display>(&self, msg: M) {
+        self.add(MessageKind::Error, format!("{}", msg));
+    }
+
+    /// Add an info message to the log.
+    pub fn info<M: Display>(&self, msg: M) {
+        self.add(MessageKind::Info, format!("{}", msg));
+    }
+
+    /// Add a status message to the log.
+    pub fn status<M: Display>(&self, msg: M) {
+        self.add(MessageKind::Status, format!("{}", msg));
+    }
+
+    /// Add a Lua message to the log.
+    pub fn lua<M: Display>(&self, msg: M) {
+        self.add(MessageKind::Lua, format!("{}", msg));
+    }
+}
+
+/// The maximum number of messages returned by a single query on the log.
+const MAX_LIMIT: usize = 1000;
+
 /// Parameters used to filter the log for certain messages.
-#[derive(Clone, Copy)]
+/// This is parsed from a JSON object like
+/// `{"kinds": ["error"], "text": "focus", "since": 1590000000, "limit": 50}`,
+/// where every key is optional.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
 pub struct Filter {
-    /// Whether to keep error messages
-    error: bool,
-    /// Whether to keep info messages
-    info: bool,
-    /// Whether to keep status messages
-    status: bool,
-    /// Whether to keep Lua messages
-    lua: bool,
+    /// Which kinds of message to keep
+    kinds: Vec<MessageKind>,
     /// Whether to keep deleted messages
     deleted: bool,
+    /// A string that the body of kept messages must contain
+    text: Option<String>,
+    /// A regular expression that the body of kept messages must match
+    #[serde(deserialize_with = "parse_regex")]
+    regex: Option<Regex>,
+    /// The earliest time (as a timestamp) of kept messages
+    since: Option<i64>,
+    /// The latest time (as a timestamp) of kept messages
+    until: Option<i64>,
+    /// The values that fields of kept messages must have
+    #[serde(flatten)]
+    fields: Fields,
+    /// The maximum number of messages to return at once (at least one)
+    limit: usize,
+    /// Only return messages older than the one with this ID. This is used
+    /// to fetch the next page of results.
+    before: Option<u64>,
 }
 
-impl Filter {
-    /// Parse this from a byte slice like b"yyyn".
-    pub fn from_body(body: &[u8]) -> Self {
+/// Deserialize a regular expression from a string.
+fn parse_regex<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+    match Option::<String>::deserialize(d)? {
+        Some(s) => Regex::new(&s).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
         Self {
-            error:   body.get(0) == Some(&b'y'),
-            info:    body.get(1) == Some(&b'y'),
-            status:  body.get(2) == Some(&b'y'),
-            lua:     body.get(3) == Some(&b'y'),
-            deleted: body.get(4) == Some(&b'y'),
+            kinds: vec![
+                MessageKind::Error,
+                MessageKind::Info,
+                MessageKind::Status,
+                MessageKind::Lua,
+            ],
+            deleted: false,
+            text: None,
+            regex: None,
+            since: None,
+            until: None,
+            fields: Fields::default(),
+            limit: 100,
+            before: None,
         }
     }
+}
+
+impl Filter {
+    /// Parse this from a JSON request body.
+    /// Return `None` if the body is malformed.
+    pub fn from_body(body: &[u8]) -> Option<Self> {
+        let mut filter: Self = serde_json::from_slice(body).ok()?;
+        // A page of no messages would never get to the next one.
+        filter.limit = filter.limit.clamp(1, MAX_LIMIT);
+        Some(filter)
+    }
 
     /// Check whether the message is permitted by the filter.
-    pub fn permits(self, msg: &Message) -> bool {
-        (self.deleted || !msg.is_deleted) && match msg.kind {
-            MessageKind::Error => self.error,
-            MessageKind::Info => self.info,
-            MessageKind::Status => self.status,
-            MessageKind::Lua => self.lua,
+    pub fn permits(&self, msg: &Message) -> bool {
+        /// Check whether a field matches the value required by the filter (if any).
+        fn matches<T: PartialEq>(required: &Option<T>, actual: &Option<T>) -> bool {
+            required.is_none() || required == actual
+        }
+        let fields = &msg.fields;
+        let time = msg.time.timestamp();
+        (self.deleted || !msg.is_deleted)
+            && self.kinds.contains(&msg.kind)
+            && self.text.as_ref().is_none_or(|text| msg.body.contains(text.as_str()))
+            && self.regex.as_ref().is_none_or(|regex| regex.is_match(&msg.body))
+            && self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until)
+            && self.before.is_none_or(|before| msg.id < before)
+            && matches(&self.fields.subsystem, &fields.subsystem)
+            && matches(&self.fields.world, &fields.world)
+            && matches(&self.fields.version, &fields.version)
+            && matches(&self.fields.focus, &fields.focus)
+            && matches(&self.fields.sim_file, &fields.sim_file)
+            && matches(&self.fields.request_id, &fields.request_id)
+    }
+
+    /// The maximum number of messages to return at once.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create an empty directory for a test to write log files to.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("nokevair-log-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Return the bodies of the messages stored in a log file, or `None` if it doesn't exist.
+    fn bodies(path: &Path) -> Option<Vec<String>> {
+        let contents = fs::read_to_string(path).ok()?;
+        Some(contents.lines()
+            .map(|line| serde_json::from_str::<Message>(line).unwrap().body)
+            .collect())
+    }
+
+    /// Write messages to a log file that is rotated before every message after the first.
+    fn write_rotating(dir: &Path, retain: u32, bodies: &[&str]) -> PathBuf {
+        let path = dir.join("log.jsonl");
+        let cfg = LogCfg { max_size: 1, retain, ..LogCfg::default() };
+        let mut file = LogFile::open(&cfg, path.clone()).unwrap();
+        for body in bodies {
+            let msg = Message::new(MessageKind::Info, body.to_string(), Fields::default());
+            file.write(&msg).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn rotation_without_retained_files() {
+        let dir = temp_dir("retain-0");
+        let path = write_rotating(&dir, 0, &["a", "b", "c"]);
+        assert_eq!(bodies(&path), Some(vec![String::from("c")]));
+        assert_eq!(bodies(&rotated_path(&path, 1)), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_with_retained_files() {
+        let dir = temp_dir("retain-2");
+        let path = write_rotating(&dir, 2, &["a", "b", "c", "d"]);
+        assert_eq!(bodies(&path), Some(vec![String::from("d")]));
+        assert_eq!(bodies(&rotated_path(&path, 1)), Some(vec![String::from("c")]));
+        assert_eq!(bodies(&rotated_path(&path, 2)), Some(vec![String::from("b")]));
+        assert_eq!(bodies(&rotated_path(&path, 3)), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn duplicates_are_written_once_per_burst() {
+        let dir = temp_dir("dedup");
+        let path = dir.join("log.jsonl");
+        let cfg = LogCfg { stderr_level: Level::Off, ..LogCfg::default() };
+        let log = Log::new();
+        log.configure(&cfg);
+        log.attach_file(&cfg, path.clone());
+        for _ in 0..5 {
+            log.err("disk full");
+        }
+        log.info("done");
+        let counts = || fs::read_to_string(&path).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Message>(line).unwrap())
+            .map(|msg| (msg.body, msg.count))
+            .collect::<Vec<_>>();
+        assert_eq!(counts(), [(String::from("disk full"), 1), (String::from("done"), 1)]);
+
+        // The burst is still open, so nothing more is written yet.
+        log.write_closed_duplicates();
+        assert_eq!(counts().len(), 2);
+
+        let later = Utc::now() + chrono::Duration::seconds(cfg.dedup_window as i64);
+        let mut file = log.file.lock().unwrap();
+        file.as_mut().unwrap().write_closed(later, cfg.dedup_window as i64).unwrap();
+        drop(file);
+        assert_eq!(counts()[2], (String::from("disk full"), 5));
+        assert_eq!(counts().len(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Parse a filter that is expected to be valid.
+    fn filter(json: &str) -> Filter {
+        Filter::from_body(json.as_bytes()).unwrap()
+    }
+
+    /// Create an info message logged at the given timestamp.
+    fn message_at(body: &str, timestamp: i64) -> Message {
+        let mut msg = Message::new(MessageKind::Info, body.to_string(), Fields::default());
+        msg.time = Utc.timestamp(timestamp, 0);
+        msg
+    }
+
+    #[test]
+    fn filter_regex() {
+        assert!(Filter::from_body(br#"{"regex": "("}"#).is_none());
+        let filter = filter(r#"{"regex": "^could not .* '[0-9]+\\.lua'$"}"#);
+        assert!(filter.permits(&message_at("could not load '12.lua'", 0)));
+        assert!(!filter.permits(&message_at("could not load 'x.lua'", 0)));
+    }
+
+    #[test]
+    fn filter_time_bounds() {
+        let msg = message_at("tick", 1000);
+        assert!(filter(r#"{"since": 1000}"#).permits(&msg));
+        assert!(!filter(r#"{"since": 1001}"#).permits(&msg));
+        assert!(filter(r#"{"until": 1000}"#).permits(&msg));
+        assert!(!filter(r#"{"until": 999}"#).permits(&msg));
+        assert!(filter(r#"{"since": 900, "until": 1100}"#).permits(&msg));
+        assert!(!filter(r#"{"since": 1100, "until": 900}"#).permits(&msg));
+    }
+
+    #[test]
+    fn filter_limit() {
+        assert_eq!(filter("{}").limit(), 100);
+        assert_eq!(filter(r#"{"limit": 0}"#).limit(), 1);
+        assert_eq!(filter(r#"{"limit": 50}"#).limit(), 50);
+        assert_eq!(filter(r#"{"limit": 5000}"#).limit(), MAX_LIMIT);
+        assert!(Filter::from_body(br#"{"limit": -1}"#).is_none());
+    }
+
+    #[test]
+    fn filter_pagination() {
+        let log = Log::new();
+        log.configure(&LogCfg { stderr_level: Level::Off, ..LogCfg::default() });
+        for i in 0..5 {
+            log.info(format_args!("message {}", i));
+        }
+        log.err("not an info message");
+
+        let mut pages = Vec::new();
+        let mut before = None;
+        loop {
+            let json = match before {
+                Some(before) => {
+                    format!(r#"{{"kinds": ["info"], "limit": 2, "before": {}}}"#, before)
+                }
+                None => String::from(r#"{"kinds": ["info"], "limit": 2}"#),
+            };
+            let (messages, next) = log.query(&filter(&json));
+            pages.push(messages.into_iter().map(|msg| msg.body).collect::<Vec<_>>());
+            before = next;
+            if before.is_none() {
+                break;
+            }
         }
+        assert_eq!(pages, [
+            vec!["message 4", "message 3"],
+            vec!["message 2", "message 1"],
+            vec!["message 0"],
+        ]);
     }
 }
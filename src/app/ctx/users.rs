@@ -0,0 +1,85 @@
+//! Tracks the accounts that are permitted to access the admin panel,
+//! and provides a mechanism for reading them from a TOML file.
+
+use serde::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::fs;
+
+use super::{Log, Cfg};
+
+/// Describes what an admin is allowed to do. Each role is permitted
+/// to do everything that the roles before it are permitted to do.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub enum Role {
+    /// May read the log and the dashboard.
+    #[serde(rename="viewer")]
+    Viewer,
+    /// May also reload templates and focuses and change rates.
+    #[serde(rename="operator")]
+    Operator,
+    /// May also change the sim file and edit states.
+    #[serde(rename="owner")]
+    Owner,
+}
+
+/// Describes the format of the users TOML file.
+#[derive(Deserialize)]
+struct Manifest {
+    /// The `users` field of the manifest file.
+    users: Vec<User>,
+}
+
+/// Tracks information about a single admin account.
+#[derive(Deserialize)]
+pub struct User {
+    /// The name used to log in.
+    pub name: String,
+    /// A random string that is combined with the password before hashing it.
+    pub salt: String,
+    /// The value of `sha256(salt + ":" + password)`, encoded as hex.
+    pub hash: String,
+    /// What the user is allowed to do.
+    pub role: Role,
+}
+
+/// Tracks every admin account.
+#[derive(Default)]
+pub struct Users {
+    /// Associates user names with their account details.
+    users: HashMap<String, User>,
+}
+
+impl Users {
+    /// Read and parse the users file.
+    pub fn load(log: &Log, cfg: &Cfg) -> Option<Self> {
+        let contents = match fs::read(&cfg.paths.users) {
+            Ok(c) => c,
+            Err(e) => { log.err(format_args!("while reading users file: {}", e)); return None }
+        };
+        let manifest: Manifest = match toml::from_slice(&contents) {
+            Ok(m) => m,
+            Err(e) => { log.err(format_args!("while parsing users file: {}", e)); return None }
+        };
+
+        let mut users = HashMap::new();
+        for user in manifest.users {
+            if users.contains_key(&user.name) {
+                log.err(format_args!("user '{}' is defined more than once", user.name));
+                return None;
+            }
+            users.insert(user.name.clone(), user);
+        }
+
+        if users.is_empty() {
+            log.info("no users are defined, so nobody can log in to the admin panel");
+        }
+
+        Some(Self { users })
+    }
+
+    /// Return the account with the given name.
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+}
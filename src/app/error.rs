@@ -5,12 +5,15 @@ use tera::Context;
 
 use std::fmt::Display;
 
+use super::ctx::log::Fields;
 use super::lua::Version;
 
 /// A custom `Result` type representing either a successful result
 /// or an HTTP error response.
 pub type Result<T> = std::result::Result<T, Response<Body>>;
 
+// Error responses are full `Response<Body>`s by design.
+#[allow(clippy::result_large_err)]
 impl super::AppState {
     /// Return an error with status code 400.
     pub(super) fn error_400<T>(&self) -> Result<T> {
@@ -26,6 +29,13 @@ impl super::AppState {
         Err(response)
     }
 
+    /// Return an error with status code 403.
+    pub(super) fn error_403<T>(&self) -> Result<T> {
+        let mut response = self.render("403.html", &Context::new())?;
+        *response.status_mut() = hyper::StatusCode::from_u16(403).unwrap();
+        Err(response)
+    }
+
     /// Return an error with status code 404.
     pub(super) fn error_404<T>(&self) -> Result<T> {
         let mut response = self.render("404.html", &Context::new())?;
@@ -33,6 +43,13 @@ impl super::AppState {
         Err(response)
     }
 
+    /// Return an error with status code 429.
+    pub(super) fn error_429<T>(&self) -> Result<T> {
+        let mut response = self.render("429.html", &Context::new())?;
+        *response.status_mut() = hyper::StatusCode::from_u16(429).unwrap();
+        Err(response)
+    }
+
     /// Return a 404 error caused by a particular version of the state
     /// not being loadable.
     pub(super) fn error_404_no_state<T>(&self, ver: Version) -> Result<T> {
@@ -45,7 +62,12 @@ impl super::AppState {
 
     /// Log a message and return an error with status code 500.
     pub(super) fn error_500<T, M: Display>(&self, msg: M) -> Result<T> {
-        self.ctx.log.err(msg);
+        self.error_500_with(Fields::default(), msg)
+    }
+
+    /// Log a message with structured fields and return an error with status code 500.
+    pub(super) fn error_500_with<T, M: Display>(&self, fields: Fields, msg: M) -> Result<T> {
+        self.ctx.log.with(fields).err(msg);
         let mut response = self.render("500.html", &Context::new())?;
         *response.status_mut() = hyper::StatusCode::from_u16(500).unwrap();
         Err(response)
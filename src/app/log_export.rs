@@ -0,0 +1,185 @@
+//! Exports the log as NDJSON or CSV so that it can be attached elsewhere.
+
+use chrono::SecondsFormat;
+use hyper::{Response, Body};
+use serde::Serialize;
+
+use super::Result;
+use super::ctx::log::{Fields, Filter, Message, MessageKind};
+
+/// A file format that the log can be exported in.
+#[derive(Clone, Copy)]
+pub enum Format {
+    /// One JSON object per line.
+    Ndjson,
+    /// Comma-separated values, with a header row.
+    Csv,
+}
+
+impl Format {
+    /// Parse a format from its name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ndjson" => Some(Self::Ndjson),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+
+    /// The MIME type of the format.
+    fn mime(self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv",
+        }
+    }
+
+    /// The extension of files in this format.
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Ndjson => "ndjson",
+            Self::Csv => "csv",
+        }
+    }
+}
+
+/// The columns of a CSV export, in order.
+const CSV_HEADER: &str = concat!(
+    "id,time,kind,is_deleted,count,last_time,",
+    "subsystem,world,version,focus,sim_file,request_id,body\n",
+);
+
+/// Describes how a message is exported. Unlike the log file, this uses
+/// ISO-8601 timestamps so that it can be read without further conversion.
+#[derive(Serialize)]
+struct Record<'a> {
+    /// The ID of the message.
+    id: u64,
+    /// The time when the message was created.
+    time: String,
+    /// What kind of message is this?
+    kind: MessageKind,
+    /// Has the admin marked this message as deleted?
+    is_deleted: bool,
+    /// How many times was this message logged?
+    count: u64,
+    /// The time when the most recent duplicate of this message was logged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_time: Option<String>,
+    /// The content of the message.
+    body: &'a str,
+    /// Structured data describing where the message came from.
+    #[serde(flatten)]
+    fields: &'a Fields,
+}
+
+/// Quote a CSV field if necessary.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Convert a value to a JSON string and strip any quotes around it.
+fn plain<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        Ok(serde_json::Value::Null) | Err(_) => String::new(),
+        Ok(other) => other.to_string(),
+    }
+}
+
+/// Format a message as one line of the export.
+fn format_message(msg: &Message, format: Format) -> String {
+    let time = msg.time.to_rfc3339_opts(SecondsFormat::Millis, true);
+    let last_time = msg.last_time.map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true));
+    match format {
+        Format::Ndjson => {
+            let record = Record {
+                id: msg.id,
+                time,
+                kind: msg.kind,
+                is_deleted: msg.is_deleted,
+                count: msg.count,
+                last_time,
+                body: &msg.body,
+                fields: &msg.fields,
+            };
+            let mut line = serde_json::to_string(&record).unwrap_or_default();
+            line.push('\n');
+            line
+        }
+        Format::Csv => {
+            let fields = &msg.fields;
+            let columns = [
+                msg.id.to_string(),
+                time,
+                plain(&msg.kind),
+                msg.is_deleted.to_string(),
+                msg.count.to_string(),
+                last_time.unwrap_or_default(),
+                plain(&fields.subsystem),
+                plain(&fields.world),
+                plain(&fields.version),
+                plain(&fields.focus),
+                plain(&fields.sim_file),
+                plain(&fields.request_id),
+                msg.body.clone(),
+            ];
+            let mut line = columns.iter()
+                .map(|c| csv_field(c))
+                .collect::<Vec<_>>()
+                .join(",");
+            line.push('\n');
+            line
+        }
+    }
+}
+
+impl super::AppState {
+    /// Generate a response to a GET request to the path "/admin/log/export/<format>".
+    /// Every message matching the filter is included (the filter's limit is
+    /// ignored), from oldest to newest.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn serve_log_export(
+        &self,
+        filter: Filter,
+        format: Format,
+    ) -> Result<Response<Body>> {
+        let mut messages = Vec::new();
+        self.ctx.log.for_each(|msg| {
+            if filter.permits(msg) {
+                messages.push(msg.clone());
+            }
+        });
+
+        let (mut tx, body) = Body::channel();
+        tokio::spawn(async move {
+            if let Format::Csv = format {
+                if tx.send_data(CSV_HEADER.into()).await.is_err() {
+                    return
+                }
+            }
+            for msg in messages.iter().rev() {
+                // This fails if the client disconnects.
+                if tx.send_data(format_message(msg, format).into()).await.is_err() {
+                    return
+                }
+            }
+        });
+
+        let file_name = format!(
+            "log-{}.{}",
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ"),
+            format.extension(),
+        );
+        Ok(Response::builder()
+            .status(200)
+            .header("Content-Type", format.mime())
+            .header("Content-Disposition", format!("attachment; filename=\"{}\"", file_name))
+            .body(body)
+            .unwrap())
+    }
+}
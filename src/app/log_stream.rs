@@ -0,0 +1,55 @@
+//! Streams new log messages to the admin panel as Server-Sent Events.
+
+use hyper::{Response, Body};
+use tokio::sync::broadcast::RecvError;
+use tokio::time::{Duration, Instant, interval_at};
+
+use super::Result;
+use super::ctx::log::Filter;
+
+/// How often a comment is sent to keep idle connections open. This is also
+/// how quickly the stream notices that the client has gone away.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+impl super::AppState {
+    /// Generate a response to a GET request to the path "/admin/log/stream".
+    /// Each message matching the filter is sent as an event whose data is the
+    /// message serialized as JSON.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn serve_log_stream(&self, filter: Filter) -> Result<Response<Body>> {
+        let mut rx = self.ctx.log.subscribe();
+        let (mut tx, body) = Body::channel();
+
+        tokio::spawn(async move {
+            // This keeps ticking however many messages arrive (and are
+            // filtered out), so a stream for a client that has gone away ends.
+            let start = Instant::now() + KEEPALIVE_INTERVAL;
+            let mut keepalive = interval_at(start, KEEPALIVE_INTERVAL);
+            loop {
+                let event = tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Ok(msg) if filter.permits(&msg) => match serde_json::to_string(&msg) {
+                            Ok(json) => format!("id: {}\ndata: {}\n\n", msg.id, json),
+                            Err(_) => continue,
+                        },
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(n)) => format!(": skipped {} messages\n\n", n),
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = keepalive.tick() => String::from(": keepalive\n\n"),
+                };
+                // This fails once the client disconnects.
+                if tx.send_data(event.into()).await.is_err() {
+                    break
+                }
+            }
+        });
+
+        Ok(Response::builder()
+            .status(200)
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .body(body)
+            .unwrap())
+    }
+}
@@ -2,79 +2,274 @@
 
 use hyper::{Response, Body};
 
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
 use tokio::time::{Instant, Duration};
 
-use super::{Result, utils};
+use std::convert::TryInto;
+use std::fs;
+use std::net::IpAddr;
+
+use super::{Ctx, Result, utils};
+use super::session::Admin;
+use super::ctx::log::Subsystem;
+
+/// Tracks information about a login challenge token.
+pub struct LoginToken {
+    /// When was the token created?
+    created: Instant,
+    /// Which client was the token given to?
+    ip: IpAddr,
+}
+
+/// Tracks the failed login attempts made by a single client.
+pub struct Attempts {
+    /// How many attempts have failed since the client last logged in
+    /// or was last locked out?
+    failures: u32,
+    /// The client may not attempt to log in again until this time.
+    blocked_until: Instant,
+    /// Is the client currently locked out (as opposed to merely waiting
+    /// out the backoff period)?
+    locked_out: bool,
+}
+
+/// Describes how lockouts are serialized when passing them to Tera.
+#[derive(Serialize)]
+pub struct TeraLockout {
+    /// The address of the client that is locked out.
+    ip: String,
+    /// How many more seconds the lockout will last.
+    secs_remaining: u64,
+}
+
+/// Read the secret from which unknown users' fake salts are derived from the
+/// file named in the config, generating it if the file doesn't exist. If it
+/// can't be read or written, a new secret is used until the server restarts.
+pub fn load_salt_key(ctx: &Ctx) -> [u8; 32] {
+    let path = &ctx.cfg().paths.salt_key;
+    let log = ctx.log.of(Subsystem::Auth);
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            let key = hex::decode(contents.trim()).ok().and_then(|key| key.try_into().ok());
+            if let Some(key) = key {
+                return key
+            }
+            log.err(format_args!("'{}' does not contain a 32-byte hex key", path.display()));
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = rand::random::<[u8; 32]>();
+            match fs::write(path, hex::encode(key)) {
+                Ok(()) => log.info(format_args!("generated salt key in '{}'", path.display())),
+                Err(e) => log.err(format_args!(
+                    "could not write salt key to '{}': {}",
+                    path.display(),
+                    e,
+                )),
+            }
+            return key
+        }
+        Err(e) => log.err(format_args!("while reading salt key: {}", e)),
+    }
+    rand::random()
+}
 
 impl super::AppState {
     /// Return a `Duration` representing the period of time after which a token
     /// is no longer considered valid.
     fn get_token_age(&self) -> Duration {
-        Duration::from_secs(self.ctx.cfg.security.auth_timeout as u64)
+        Duration::from_secs(self.ctx.cfg().security.auth_timeout as u64)
     }
 
     /// Generate a unique token with which to challenge the client for the password.
-    pub(super) fn gen_login_token(&self) -> u64 {
+    /// If the client already holds the maximum number of tokens, the oldest one
+    /// is discarded.
+    pub(super) fn gen_login_token(&self, ip: IpAddr) -> u64 {
         let token = rand::random();
-        self.login_tokens.write().insert(token, Instant::now());
+        let mut logins = self.login_tokens.write();
+
+        let max_tokens = self.ctx.cfg().security.max_tokens_per_ip as usize;
+        let mut held = logins.iter()
+            .filter(|(_, login)| login.ip == ip)
+            .map(|(&token, login)| (login.created, token))
+            .collect::<Vec<_>>();
+        // Make room for the new token. (A limit of zero acts like a limit of one.)
+        let excess = (held.len() + 1).saturating_sub(max_tokens);
+        if excess > 0 {
+            held.sort();
+            for (_, token) in &held[..excess.min(held.len())] {
+                logins.remove(token);
+            }
+        }
+
+        logins.insert(token, LoginToken { created: Instant::now(), ip });
         token
     }
 
-    /// Remove any login tokens that are older than the specified maximum.
+    /// Remove any login tokens that are older than the specified maximum,
+    /// and forget about failed attempts by clients whose lockouts have expired.
     pub(super) fn clear_login_tokens(&self) {
         let mut logins = self.login_tokens.write();
         let num_logins = logins.len();
-        logins.retain(|_, creation_time| creation_time.elapsed() < self.get_token_age());
+        logins.retain(|_, login| login.created.elapsed() < self.get_token_age());
         let num_cleared = num_logins - logins.len();
         if num_cleared > 0 {
-            self.ctx.log.status(format!(
+            self.ctx.log.of(Subsystem::Auth).status(format!(
                 "cleared {} login token{}",
                 num_cleared,
                 if num_cleared == 1 { "" } else { "s" }
             ))
         }
+
+        let forget_after = self.get_lockout_duration();
+        self.login_attempts.write().retain(|_, attempts| {
+            attempts.blocked_until + forget_after > Instant::now()
+        });
     }
 
-    /// Generate a response to a login attempt.
-    pub(super) fn login(&self, body: Vec<u8>) -> Result<Response<Body>> {
-        let password = match self.ctx.cfg.security.login_password.clone() {
-            Some(pw) => pw,
-            None => {
-                self.ctx.log.info("cannot authenticate - no admin password provided");
-                return self.error_401();
-            }
+    /// Return a `Duration` representing how long a client is locked out for
+    /// after making too many failed login attempts.
+    fn get_lockout_duration(&self) -> Duration {
+        Duration::from_secs(self.ctx.cfg().security.lockout_duration as u64)
+    }
+
+    /// Return whether the client must wait before attempting to log in again.
+    fn is_login_blocked(&self, ip: IpAddr) -> bool {
+        match self.login_attempts.read().get(&ip) {
+            Some(attempts) => attempts.blocked_until > Instant::now(),
+            None => false,
+        }
+    }
+
+    /// Record a failed login attempt. The client must wait exponentially longer
+    /// after each failure, and is locked out once the failures reach a threshold.
+    fn record_login_failure(&self, ip: IpAddr) {
+        let security = &self.ctx.cfg().security;
+        let mut all_attempts = self.login_attempts.write();
+        let attempts = all_attempts.entry(ip).or_insert(Attempts {
+            failures: 0,
+            blocked_until: Instant::now(),
+            locked_out: false,
+        });
+
+        attempts.failures += 1;
+        if attempts.failures >= security.lockout_threshold {
+            attempts.failures = 0;
+            attempts.blocked_until = Instant::now() + self.get_lockout_duration();
+            attempts.locked_out = true;
+            self.ctx.log.of(Subsystem::Auth).info(format_args!(
+                "locked out {} for {} secs after {} failed login attempts",
+                ip,
+                security.lockout_duration,
+                security.lockout_threshold,
+            ));
+        } else {
+            let backoff = (security.backoff_base as u64)
+                .saturating_mul(1 << (attempts.failures - 1).min(32))
+                .min(security.lockout_duration as u64);
+            attempts.blocked_until = Instant::now() + Duration::from_secs(backoff);
+            attempts.locked_out = false;
+        }
+    }
+
+    /// Return the clients that are currently locked out.
+    pub(super) fn lockouts(&self) -> Vec<TeraLockout> {
+        let now = Instant::now();
+        let mut lockouts = self.login_attempts.read()
+            .iter()
+            .filter(|(_, attempts)| attempts.locked_out && attempts.blocked_until > now)
+            .map(|(ip, attempts)| TeraLockout {
+                ip: ip.to_string(),
+                secs_remaining: (attempts.blocked_until - now).as_secs(),
+            })
+            .collect::<Vec<_>>();
+        lockouts.sort_by(|a, b| a.ip.cmp(&b.ip));
+        lockouts
+    }
+
+    /// Return the salt that the client should combine with the password of the
+    /// given user. To avoid revealing which accounts exist, unknown users are
+    /// given a salt that is consistent (even across restarts) but meaningless.
+    pub(super) fn login_salt(&self, name: &str) -> Response<Body> {
+        let salt = match self.users.get(name) {
+            Some(user) => user.salt.clone(),
+            None => utils::hmac_sha256(&self.salt_key, name)[..12].to_string(),
         };
+        Response::builder()
+            .status(200)
+            .header("Content-Type", "text/plain")
+            .body(Body::from(salt))
+            .unwrap()
+    }
+
+    /// Generate a response to a login attempt.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn login(&self, ip: IpAddr, body: Vec<u8>) -> Result<Response<Body>> {
+        if self.is_login_blocked(ip) {
+            return self.error_429();
+        }
 
         /// Describes the format of authentication requests.
         #[derive(Deserialize)]
         struct LoginData {
+            /// The name of the account to log in to.
+            user: String,
             /// The token provided by `/login`.
             token: String,
-            /// A value that must be equal to `hash(token + ":" + password)`
+            /// A value that must be equal to `hash(token + ":" + hash(salt + ":" + password))`
             /// in order to correctly authenticate.
             hash: String,
+            /// A TOTP code or recovery code, required if the account has enrolled in TOTP.
+            #[serde(default)]
+            code: Option<String>,
         }
 
-        let LoginData { token, hash } = serde_json::from_slice(&body)
+        let LoginData { user, token, hash, code } = serde_json::from_slice(&body)
             .or_else(|_| self.error_400())?;
         let token: u64 = token.parse()
             .or_else(|_| self.error_400())?;
         let logins = self.login_tokens.read();
-        let creation_time = logins.get(&token).ok_or(())
+        let login = logins.get(&token).ok_or(())
             .or_else(|_| self.error_401())?;
         
-        if creation_time.elapsed() > self.get_token_age() {
+        if login.ip != ip || login.created.elapsed() > self.get_token_age() {
             self.error_401()?;
         }
 
-        let msg = format!("{}:{}", token, password);
-        if utils::sha256(&msg) != hash {
-            self.ctx.log.info("authentication attempt was rejected");
+        let user = match self.users.get(&user) {
+            Some(u) => u,
+            None => {
+                self.ctx.log.of(Subsystem::Auth).info(format_args!(
+                    "authentication attempt by {} for unknown user '{}' was rejected",
+                    ip,
+                    user,
+                ));
+                self.record_login_failure(ip);
+                return self.error_401();
+            }
+        };
+
+        let msg = format!("{}:{}", token, user.hash);
+        if !utils::constant_time_eq(&utils::sha256(&msg), &hash) {
+            self.ctx.log.of(Subsystem::Auth).info(format_args!(
+                "authentication attempt by {} for user '{}' was rejected",
+                ip,
+                user.name,
+            ));
+            self.record_login_failure(ip);
+            self.error_401()?
+        } else if !self.check_totp(&user.name, code.as_deref().filter(|c| !c.is_empty())) {
+            self.ctx.log.of(Subsystem::Auth).info(format_args!(
+                "authentication attempt by {} for user '{}' had an invalid second factor",
+                ip,
+                user.name,
+            ));
+            self.record_login_failure(ip);
             self.error_401()?
         } else {
-            self.ctx.log.info("user was authenticated");
-            Ok(Self::redirect("/admin"))
+            self.ctx.log.of(Subsystem::Auth).info(format_args!(
+                "user '{}' was authenticated", user.name));
+            self.login_attempts.write().remove(&ip);
+            Ok(self.start_session(Admin::new(user), ip, "/admin"))
         }
     }
 }
@@ -1,5 +1,6 @@
 //! Use `rlua` to start a Lua instance and permit other tasks to query it.
 
+use futures::future::join_all;
 use hyper::{Response, Body};
 use parking_lot::RwLock;
 use rlua::{Lua, RegistryKey};
@@ -15,6 +16,7 @@ use std::time::Duration;
 use crate::conv;
 use crate::utils::SourceChain;
 use super::{Ctx, Result, AppState};
+use super::ctx::log::{self, Subsystem};
 
 pub mod render;
 
@@ -24,7 +26,7 @@ mod version;
 pub use version::Version;
 
 /// Create a new Lua instance with several predefined functions.
-fn create_lua_state(app_ctx: &Ctx) -> Lua {
+pub(super) fn create_lua_state(app_ctx: &Ctx) -> Lua {
     let lua = Lua::new();
     lua.context(|ctx| {
         let globals = ctx.globals();
@@ -85,6 +87,8 @@ enum Req {
         /// The value of the `i` parameter passed in the URL
         /// via query string, if present
         query_param: Option<String>,
+        /// The ID of the HTTP request being handled, if known
+        request_id: Option<u64>,
         /// The channel over which to send a response.
         resp_tx: oneshot::Sender<Response<Body>>,
     },
@@ -151,7 +155,8 @@ impl Frontend {
         query_param: Option<String>,
     ) -> Option<Response<Body>> {
         let (resp_tx, resp_rx) = oneshot::channel();
-        let req = Req::Render { ver, name, query_param, resp_tx };
+        let request_id = log::REQUEST_ID.try_with(|id| *id).ok();
+        let req = Req::Render { ver, name, query_param, request_id, resp_tx };
         self.tx.clone().send(req).await.ok()?;
         resp_rx.await.ok()
     }
@@ -194,8 +199,10 @@ impl Frontend {
     }
 }
 
-/// The state held by the Lua backend thread.
+/// The state held by the Lua backend thread of a world.
 pub struct Backend {
+    /// The position of the world that the backend belongs to in `AppState::worlds`.
+    world: usize,
     /// The main `Lua` instance.
     lua: Lua,
     /// The versions of the world state currently loaded in the registry.
@@ -207,9 +214,11 @@ pub struct Backend {
 }
 
 impl Backend {
-    /// Create the backend.
-    fn new(rx: Rx, ctx: &Ctx) -> Self {
+    /// Create the backend for a world, given the context belonging to it and
+    /// its position in `AppState::worlds`.
+    fn new(rx: Rx, ctx: &Ctx, world: usize) -> Self {
         let mut self_ = Self {
+            world,
             lua: create_lua_state(ctx),
             state_versions: VecMap::new(),
             rx,
@@ -223,18 +232,19 @@ impl Backend {
     /// specified version, convert it to a Lua object, and put it in the registry.
     fn load_from_file(&self, ver: Version, app_ctx: &Ctx) -> Option<RegistryKey> {
         let path = ver.path(app_ctx);
+        let log = app_ctx.log.with(app_ctx.fields(Subsystem::Render).version(ver.as_usize()));
 
-        app_ctx.log.info(format_args!("loading lua state from file '{}'", path.display()));
+        log.info(format_args!("loading lua state from file '{}'", path.display()));
 
         if !Path::new(&path).exists() {
-            app_ctx.log.err("file does not exist");
+            log.err("file does not exist");
             return None;
         }
 
         let mut file = match File::open(&path) {
             Ok(file) => file,
             Err(e) => {
-                app_ctx.log.err(format_args!("file could not be opened: {}", e));
+                log.err(format_args!("file could not be opened: {}", e));
                 return None
             }
         };
@@ -242,7 +252,7 @@ impl Backend {
         let mpv = match conv::bytes_to_msgpack(&mut file) {
             Ok(file) => file,
             Err(e) => {
-                app_ctx.log.err(format_args!("file could not be read as msgpack: {}", e));
+                log.err(format_args!("file could not be read as msgpack: {}", e));
                 return None
             }
         };
@@ -251,7 +261,7 @@ impl Backend {
             let lv = match conv::msgpack_to_lua(mpv, ctx) {
                 Ok(lv) => lv,
                 Err(e) => {
-                    app_ctx.log.err(format_args!(
+                    log.err(format_args!(
                         "lua (msgpack -> obj):\n{}",
                         SourceChain(e)
                     ));
@@ -262,7 +272,7 @@ impl Backend {
             match ctx.create_registry_value(lv) {
                 Ok(key) => Some(key),
                 Err(e) => {
-                    app_ctx.log.err(format_args!(
+                    log.err(format_args!(
                         "lua (obj -> registry):\n{}",
                         SourceChain(e)
                     ));
@@ -285,6 +295,7 @@ impl Backend {
 
     /// Create a future that continuously handles requests until the `Frontend` is dropped.
     pub async fn run(&mut self, app_state: &AppState) {
+        let ctx = &app_state.worlds[self.world].ctx;
         while let Some(req) = self.rx.recv().await {
             // Warning: when using `app_state` here, keep in mind that there are currently
             // other tasks blocking on receiving a response from here, so there is
@@ -292,28 +303,28 @@ impl Backend {
             match req {
                 Req::ReloadFocuses => {
                     self.unload_focuses();
-                    self.load_focuses(&app_state.ctx);
+                    self.load_focuses(ctx);
                 }
 
-                Req::Render { ver, name, query_param, resp_tx } => {
-                    let resp = match self.render(ver, &name, query_param, app_state) {
+                Req::Render { ver, name, query_param, request_id, resp_tx } => {
+                    let resp = match self.render(ver, &name, query_param, request_id, app_state) {
                         Ok(resp) => resp,
                         Err(resp) => resp,
                     };
                     if resp_tx.send(resp).is_err() {
-                        app_state.ctx.log.err("couldn't send response to render request");
+                        ctx.log.err("couldn't send response to render request");
                     }
                 }
 
                 Req::GetNumFocuses { resp_tx } => {
                     if resp_tx.send(self.focuses.len()).is_err() {
-                        app_state.ctx.log.err("couldn't send response to request for focuses");
+                        ctx.log.err("couldn't send response to request for focuses");
                     }
                 }
 
                 Req::GetNumStates { resp_tx } => {
                     if resp_tx.send(self.state_versions.len()).is_err() {
-                        app_state.ctx.log.err("couldn't send response to request for states");
+                        ctx.log.err("couldn't send response to request for states");
                     }
                 }
             }
@@ -321,8 +332,15 @@ impl Backend {
     }
 }
 
-/// Create a new frontend and backend.
-pub fn init(ctx: &Ctx) -> (Frontend, Backend) {
+/// Create a future that handles requests to every backend until their
+/// `Frontend`s are dropped.
+pub async fn run_all(backends: &mut [Backend], app_state: &AppState) {
+    join_all(backends.iter_mut().map(|backend| backend.run(app_state))).await;
+}
+
+/// Create a new frontend and backend for a world, given the context
+/// belonging to it and its position in `AppState::worlds`.
+pub fn init(ctx: &Ctx, world: usize) -> (Frontend, Backend) {
     let (tx, rx) = mpsc::channel(100);
-    (Frontend::new(tx), Backend::new(rx, ctx))
+    (Frontend::new(tx), Backend::new(rx, ctx, world))
 }
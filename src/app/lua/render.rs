@@ -24,15 +24,16 @@ use std::path::PathBuf;
 use crate::conv;
 use crate::utils::SourceChain;
 use super::{Ctx, Version, Result, AppState};
+use super::log::Subsystem;
 
 /// Apply a function to certain paths in the `render` directory
-/// which correspond to renderer entries.
+/// of the context's world which correspond to renderer entries.
 /// 
 /// When `f` is invoked, the first argument is the name of the
 /// entry, and the second is the path to its directory.
 pub fn with_entries<F: FnMut(String, PathBuf)>(app_ctx: &Ctx, mut f: F) {
     // Get an iterator over the contents in the `render` directory.
-    let dir = match fs::read_dir(&app_ctx.cfg.paths.render) {
+    let dir = match fs::read_dir(app_ctx.world(&app_ctx.cfg()).render) {
         Ok(dir) => dir,
         Err(e) => {
             app_ctx.log.err(format_args!("failed to read render dir: {}", e));
@@ -85,12 +86,14 @@ impl super::Backend {
     /// to the return values of executing `/render/*/focus.lua`.
     pub(super) fn load_focuses(&mut self, app_ctx: &Ctx) {
         with_entries(app_ctx, |name, mut path| {
+            let log = app_ctx.log.with(app_ctx.fields(Subsystem::Render).focus(name.as_str()));
+
             // Read the file `focus.lua` inside that directory.
             path.push("focus.lua");
             let code = match fs::read_to_string(&path) {
                 Ok(code) => code,
                 Err(e) => {
-                    app_ctx.log.err(format_args!(
+                    log.err(format_args!(
                         "failed to read file '{}': {}",
                         path.display(),
                         e
@@ -111,7 +114,7 @@ impl super::Backend {
             });
 
             if let Err(e) = res {
-                app_ctx.log.err(format_args!(
+                log.err(format_args!(
                     "lua ('{}' -> focus):\n{}",
                     path.display(),
                     SourceChain(e),
@@ -120,7 +123,7 @@ impl super::Backend {
         });
 
         let len = self.focuses.len();
-        app_ctx.log.info(format_args!(
+        app_ctx.log.with(app_ctx.fields(Subsystem::Render)).info(format_args!(
             "loaded {} focus function{}",
             len,
             if len == 1 { "" } else { "s" }
@@ -129,11 +132,13 @@ impl super::Backend {
 
     /// Invoke the renderer to generate a response for a specified path,
     /// specified version of the state, and specified query parameter.
+    #[allow(clippy::result_large_err)]
     pub(super) fn render(
         &mut self,
         ver: Version,
         name: &str,
         query_param: Option<String>,
+        request_id: Option<u64>,
         app_state: &AppState,
     ) -> Result<Response<Body>> {
         /// Helper macro to generate a description of the render request
@@ -146,7 +151,13 @@ impl super::Backend {
             }
         }
 
-        self.ensure_loaded(ver, &app_state.ctx);
+        let world = &app_state.worlds[self.world];
+        let mut fields = world.ctx.fields(Subsystem::Render)
+            .version(ver.as_usize())
+            .focus(name);
+        fields.request_id = request_id;
+
+        self.ensure_loaded(ver, &world.ctx);
 
         self.lua.context(|ctx| {
             // Look up the focus function
@@ -154,18 +165,18 @@ impl super::Backend {
                 .ok_or(())
                 .or_else(|_| app_state.error_404())?;
             let focus_fn: rlua::Function = ctx.registry_value(focus_fn_key)
-                .or_else(|_| app_state.error_500("invalid focus fn key"))?;
+                .or_else(|_| app_state.error_500_with(fields.clone(), "invalid focus fn key"))?;
             
             // Look up the state
             let state_key = self.state_versions.get(ver.as_usize())
                 .ok_or(())
                 .or_else(|_| app_state.error_404_no_state(ver))?;
             let state: LV = ctx.registry_value(state_key)
-                .or_else(|_| app_state.error_500("invalid state key"))?;
+                .or_else(|_| app_state.error_500_with(fields.clone(), "invalid state key"))?;
             
             // Apply the function to the state and query param
             let ctx: Option<rlua::Table> = focus_fn.call((state, query_param.clone()))
-                .or_else(|e| app_state.error_500(format_args!(
+                .or_else(|e| app_state.error_500_with(fields.clone(), format_args!(
                     "lua (focus {}):\n{}",
                     render_call!(),
                     SourceChain(e),
@@ -176,25 +187,31 @@ impl super::Backend {
 
             // Convert the context from a Lua value to JSON
             let ctx = conv::lua_to_json(LV::Table(ctx))
-                .or_else(|e| app_state.error_500(format_args!(
+                .or_else(|e| app_state.error_500_with(fields.clone(), format_args!(
                     "lua (focus {} -> JSON):\n{}",
                     render_call!(),
                     SourceChain(e)
                 )))?;
             
             // Convert the JSON to a Tera context.
-            let ctx = tera::Context::from_serialize(ctx)
-                .or_else(|e| app_state.error_500(format_args!(
+            let mut ctx = tera::Context::from_serialize(ctx)
+                .or_else(|e| app_state.error_500_with(fields.clone(), format_args!(
                     "tera (focus {} -> Tera ctx):\n{}",
                     render_call!(),
                     SourceChain(e),
                 )))?;
                 
+            // Let templates link to other pages in the same world.
+            let prefix = world.ctx.world(&world.ctx.cfg()).prefix
+                .map_or_else(String::new, |prefix| format!("/{}", prefix));
+            ctx.insert("world", world.ctx.world_name());
+            ctx.insert("world_prefix", &prefix);
+
             // TODO: add additional variables to the context, such as what version
             // of the state we're using
             
             let template = format!("render/{}.html", name);
-            app_state.render(&template, &ctx)
+            app_state.render_in(world, &template, &ctx)
         })
     }
 }
@@ -17,18 +17,21 @@
 
 use std::fs::{self, File};
 use std::sync::{Arc, Mutex, PoisonError};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use crate::conv;
 use crate::utils::{self, SourceChain};
 use super::{Ctx, Version};
+use super::log::Subsystem;
 
 /// Stores config info for the simulation.
 pub struct Sim {
     /// Used to let the previously-created simulation thread know that it should exit.
     cancel_previous: Mutex<Arc<AtomicBool>>, // TODO: arc_swap?
+    /// How many simulation ticks in a row have failed to write a new state file?
+    consecutive_failures: Arc<AtomicU64>,
 }
 
 impl Sim {
@@ -38,9 +41,15 @@ impl Sim {
             // This is a dummy value and will be discarded after the
             // first simulation starts.
             cancel_previous: Mutex::default(),
+            consecutive_failures: Arc::default(),
         }
     }
 
+    /// Return how many simulation ticks in a row have failed.
+    pub fn consecutive_failures(&self) -> u64 {
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
+
     /// Execute one iteration of the simulation in a new thread. If the former
     /// simulation thread is not done executing, let it know that it should stop.
     pub fn run(&self, app_ctx: Ctx) {
@@ -54,154 +63,184 @@ impl Sim {
             Arc::clone(&*cancel_previous)
         };
 
-        // Get the path of the simulation file
-        let lua_file = app_ctx.cfg.paths.sim.join(&*app_ctx.cfg.runtime.sim_file.read());
-        let lua_file_string = lua_file.display().to_string();
+        let consecutive_failures = Arc::clone(&self.consecutive_failures);
 
         let time_limit = Duration::from_secs(
-            app_ctx.cfg.runtime.sim_rate.load(Ordering::Relaxed) as u64);
-        
+            app_ctx.world(&app_ctx.cfg()).runtime.sim_rate.load(Ordering::Relaxed) as u64);
+
         thread::Builder::new()
-            .name("simulation".into())
+            .name(format!("simulation ({})", app_ctx.world_name()))
             .spawn(move || {
-                let lua = super::create_lua_state(&app_ctx);
-
-                let start_time = Instant::now();
-                
-                // Every 1000 lua instructions, check that this thread hasn't been cancelled
-                // or run out of time
-                let mut triggers = rlua::HookTriggers::default();
-                triggers.every_nth_instruction = Some(1000);
-                lua.set_hook(triggers, move |_, _| {
-                    if is_cancelled.load(Ordering::Relaxed) {
-                        Err(rlua::Error::RuntimeError(String::from("cancelled")))
-                    } else if start_time.elapsed() > time_limit {
-                        Err(rlua::Error::RuntimeError(String::from("out of time")))
-                    } else {
-                        Ok(())
-                    }
-                });
-                
-                let res = lua.context::<_, rlua::Result<()>>(|ctx| {
-                    use rlua::Value as LV;
-
-                    // Read the MessagePack file containing the latest version of the state.
-                    let next_ver = Version::next_available(&app_ctx);
-                    let current_state = match next_ver.previous() {
-                        None => {
-                            app_ctx.log.status("no state files found; using fresh state");
-                            LV::Nil
-                        }
-                        Some(ver) => {
-                            let state_path = ver.path(&app_ctx);
-                            app_ctx.log.status(format_args!(
-                                "using '{}' for simulation",
-                                state_path.display(),
-                            ));
-
-                            let mut state_file = match File::open(&state_path) {
-                                Ok(file) => file,
-                                Err(e) => {
-                                    app_ctx.log.err(format_args!(
-                                        "file could not be opened: {}",
-                                        e
-                                    ));
-                                    return Ok(())
-                                }
-                            };
-
-                            let mpv = match conv::bytes_to_msgpack(&mut state_file) {
-                                Ok(mpv) => mpv,
-                                Err(e) => {
-                                    app_ctx.log.err(format_args!(
-                                        "file could not be read as msgpack: {}",
-                                        e
-                                    ));
-                                    return Ok(())
-                                }
-                            };
-
-                            match conv::msgpack_to_lua(mpv, ctx) {
-                                Ok(lv) => lv,
-                                Err(e) => {
-                                    app_ctx.log.err(format_args!(
-                                        "lua (msgpack -> obj):\n{}",
-                                        SourceChain(e)
-                                    ));
-                                    return Ok(())
-                                }
-                            }
-                        }
-                    };
-
-                    // Read the Lua file that defines the simulation.
-                    let sim_code = match fs::read_to_string(&lua_file) {
-                        Ok(code) => code,
-                        Err(e) => {
-                            app_ctx.log.err(format_args!(
-                                "could not read simulation code in '{}': {}",
-                                lua_file_string,
-                                e
-                            ));
-                            return Ok(())
-                        }
-                    };
-
-                    // Evaluate the Lua code to get a function.
-                    let sim_fn = ctx.load(&sim_code)
-                        .set_name(&lua_file_string)?
-                        .eval::<rlua::Function>()?;
-                    
-                    // Apply this function to the state to get the new state.
-                    let new_state = sim_fn.call::<_, LV>((current_state, lua_file_string))?;
-
-                    // Convert this state back into a MessagePack object.
-                    let mpv = conv::lua_to_msgpack(new_state)?;
-
-                    let real_next_ver = Version::next_available(&app_ctx);
-
-                    if next_ver != real_next_ver {
-                        app_ctx.log.info(format_args!(
-                            "writing to '{}' instead of '{}' as was originally intended",
-                            real_next_ver.path(&app_ctx).display(),
-                            next_ver.path(&app_ctx).display(),
-                        ))
+                if tick(&app_ctx, is_cancelled, Some(time_limit)) {
+                    consecutive_failures.store(0, Ordering::Relaxed);
+                } else {
+                    consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                }
+            }).expect("failed to start simulation thread");
+    }
+}
+
+/// Execute one iteration of the simulation on the current thread, without a
+/// time limit. Return whether a new state file was written.
+pub fn run_once(app_ctx: &Ctx) -> bool {
+    tick(app_ctx, Arc::default(), None)
+}
+
+/// Execute one iteration of the simulation, stopping early if `is_cancelled`
+/// is set or the time limit runs out. Return whether a new state file was written.
+fn tick(app_ctx: &Ctx, is_cancelled: Arc<AtomicBool>, time_limit: Option<Duration>) -> bool {
+    // Get the path of the simulation file
+    let cfg = app_ctx.cfg();
+    let world = app_ctx.world(&cfg);
+    let sim_file = world.runtime.sim_file.read().clone();
+    let lua_file = world.sim.join(&sim_file);
+    let lua_file_string = lua_file.display().to_string();
+    let fields = app_ctx.fields(Subsystem::Sim).sim_file(sim_file.display().to_string());
+
+    let lua = super::create_lua_state(app_ctx);
+    let log = app_ctx.log.with(fields.clone());
+
+    let start_time = Instant::now();
+    
+    // Every 1000 lua instructions, check that this thread hasn't been cancelled
+    // or run out of time
+    let triggers = rlua::HookTriggers {
+        every_nth_instruction: Some(1000),
+        ..Default::default()
+    };
+    lua.set_hook(triggers, move |_, _| {
+        if is_cancelled.load(Ordering::Relaxed) {
+            Err(rlua::Error::RuntimeError(String::from("cancelled")))
+        } else if time_limit.is_some_and(|limit| start_time.elapsed() > limit) {
+            Err(rlua::Error::RuntimeError(String::from("out of time")))
+        } else {
+            Ok(())
+        }
+    });
+    
+    let mut succeeded = false;
+    let res = lua.context::<_, rlua::Result<()>>(|ctx| {
+        use rlua::Value as LV;
+
+        // Read the MessagePack file containing the latest version of the state.
+        let next_ver = Version::next_available(app_ctx);
+        let log = app_ctx.log.with(fields.version(next_ver.as_usize()));
+        let current_state = match next_ver.previous() {
+            None => {
+                log.status("no state files found; using fresh state");
+                LV::Nil
+            }
+            Some(ver) => {
+                let state_path = ver.path(app_ctx);
+                log.status(format_args!(
+                    "using '{}' for simulation",
+                    state_path.display(),
+                ));
+
+                let mut state_file = match File::open(&state_path) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        log.err(format_args!(
+                            "file could not be opened: {}",
+                            e
+                        ));
+                        return Ok(())
                     }
+                };
 
-                    let path = real_next_ver.path(&app_ctx);
-                    let mut new_state_file = match File::create(&path) {
-                        Ok(file) => file,
-                        Err(e) => {
-                            app_ctx.log.err(format_args!(
-                                "could not create file '{}': {}",
-                                path.display(),
-                                e
-                            ));
-                            return Ok(());
-                        }
-                    };
-
-                    if let Err(e) = conv::msgpack_to_bytes(&mut new_state_file, &mpv) {
-                        app_ctx.log.err(format_args!(
-                            "could not write state to file '{}': {}",
-                            path.display(),
+                let mpv = match conv::bytes_to_msgpack(&mut state_file) {
+                    Ok(mpv) => mpv,
+                    Err(e) => {
+                        log.err(format_args!(
+                            "file could not be read as msgpack: {}",
                             e
                         ));
-                    } else {
-                        app_ctx.log.status(format_args!(
-                            "wrote new state file '{}'",
-                            path.display()
+                        return Ok(())
+                    }
+                };
+
+                match conv::msgpack_to_lua(mpv, ctx) {
+                    Ok(lv) => lv,
+                    Err(e) => {
+                        log.err(format_args!(
+                            "lua (msgpack -> obj):\n{}",
+                            SourceChain(e)
                         ));
+                        return Ok(())
                     }
+                }
+            }
+        };
+
+        // Read the Lua file that defines the simulation.
+        let sim_code = match fs::read_to_string(&lua_file) {
+            Ok(code) => code,
+            Err(e) => {
+                log.err(format_args!(
+                    "could not read simulation code in '{}': {}",
+                    lua_file_string,
+                    e
+                ));
+                return Ok(())
+            }
+        };
+
+        // Evaluate the Lua code to get a function.
+        let sim_fn = ctx.load(&sim_code)
+            .set_name(&lua_file_string)?
+            .eval::<rlua::Function>()?;
+        
+        // Apply this function to the state to get the new state.
+        let new_state = sim_fn.call::<_, LV>((current_state, lua_file_string))?;
 
-                    Ok(())
-                });
+        // Convert this state back into a MessagePack object.
+        let mpv = conv::lua_to_msgpack(new_state)?;
 
-                if let Err(e) = res {
-                    app_ctx.log.err(format!("lua (sim):\n{}", SourceChain(e)));
-                }
-            }).expect("failed to start simulation thread");
+        let real_next_ver = Version::next_available(app_ctx);
+
+        if next_ver != real_next_ver {
+            log.info(format_args!(
+                "writing to '{}' instead of '{}' as was originally intended",
+                real_next_ver.path(app_ctx).display(),
+                next_ver.path(app_ctx).display(),
+            ))
+        }
+
+        let path = real_next_ver.path(app_ctx);
+        let mut new_state_file = match File::create(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                log.err(format_args!(
+                    "could not create file '{}': {}",
+                    path.display(),
+                    e
+                ));
+                return Ok(());
+            }
+        };
+
+        if let Err(e) = conv::msgpack_to_bytes(&mut new_state_file, &mpv) {
+            log.err(format_args!(
+                "could not write state to file '{}': {}",
+                path.display(),
+                e
+            ));
+        } else {
+            log.status(format_args!(
+                "wrote new state file '{}'",
+                path.display()
+            ));
+            succeeded = true;
+        }
+
+        Ok(())
+    });
+
+    if let Err(e) = res {
+        log.err(format!("lua (sim):\n{}", SourceChain(e)));
     }
+
+    succeeded
 }
 
 /// Determine whether a particular string represents a valid name
@@ -214,9 +253,9 @@ pub fn is_valid_name(name: &str) -> bool {
     }
 }
 
-/// Return the names of all `*.lua` files in the sim directory.
+/// Return the names of all `*.lua` files in the sim directory of the context's world.
 pub fn list_files(ctx: &Ctx) -> Vec<String> {
-    let entries = match fs::read_dir(&ctx.cfg.paths.sim) {
+    let entries = match fs::read_dir(ctx.world(&ctx.cfg()).sim) {
         Ok(en) => en,
         Err(e) => {
             ctx.log.err(format_args!("failed to read sim dir: {}", e));
@@ -21,11 +21,11 @@ impl Version {
         self.0.checked_sub(1).map(Self)
     }
 
-    /// Return the path associated with this version of the state.
+    /// Return the path associated with this version of the state
+    /// in the context's world.
     pub fn path(self, ctx: &Ctx) -> PathBuf {
-        let mut path = ctx.cfg.paths.state.clone();
-        path.push(format!("{}.msgpack", self.0));
-        path
+        let cfg = ctx.cfg();
+        ctx.world(&cfg).state.join(format!("{}.msgpack", self.0))
     }
 
     /// Return the first version with no associated state file.
@@ -43,6 +43,12 @@ impl Version {
     }
 }
 
+impl From<u32> for Version {
+    fn from(n: u32) -> Self {
+        Self(n)
+    }
+}
+
 impl FromStr for Version {
     type Err = std::num::ParseIntError;
 
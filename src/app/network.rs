@@ -0,0 +1,55 @@
+//! Utilities for restricting which clients and listeners may access
+//! which groups of routes.
+
+use std::net::IpAddr;
+
+use crate::hyper_boilerplate::Listener;
+
+/// Describes a group of routes that share an access policy.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RouteGroup {
+    /// Routes that are not part of another group.
+    Public,
+    /// `/login` and `/logout`.
+    Login,
+    /// `/admin` and everything under it.
+    Admin,
+}
+
+impl RouteGroup {
+    /// Determine which group a request path belongs to.
+    fn of(path: &[&str]) -> Self {
+        match path.first() {
+            Some(&"login") | Some(&"logout") => Self::Login,
+            Some(&"admin") => Self::Admin,
+            _ => Self::Public,
+        }
+    }
+}
+
+impl super::AppState {
+    /// Check whether a client at `ip` may access `path` through `listener`.
+    /// 
+    /// If a separate admin address is configured, the login and admin routes
+    /// are only served by its listener. Each group of routes is also restricted
+    /// to the address ranges listed for it in the config.
+    pub(super) fn is_route_permitted(&self, path: &[&str], ip: IpAddr, listener: Listener) -> bool {
+        let network = &self.ctx.cfg().network;
+        let group = RouteGroup::of(path);
+
+        let has_admin_listener = network.admin_addr.is_some();
+        if has_admin_listener && group != RouteGroup::Public && listener != Listener::Admin {
+            return false;
+        }
+
+        let allowlist = match group {
+            RouteGroup::Public => &network.allow.public,
+            RouteGroup::Login => &network.allow.login,
+            RouteGroup::Admin => &network.allow.admin,
+        };
+        match allowlist {
+            Some(ranges) => ranges.iter().any(|range| range.contains(ip)),
+            None => true,
+        }
+    }
+}
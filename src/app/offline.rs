@@ -0,0 +1,203 @@
+//! Operations that are run from the command line instead of over HTTP, so that
+//! the world can be scripted and tested without running the server. Each one
+//! logs its problems and returns whether it succeeded.
+
+use serde_json::Value as JV;
+
+use std::fs::{self, File};
+use std::io::{self, Read as _, Write as _};
+use std::path::Path;
+
+use crate::conv;
+use super::{AppState, Ctx};
+use super::ctx::log::Subsystem;
+use super::lua::{self, Version};
+
+/// Run one simulation tick and write the next state file.
+pub fn sim_once(ctx: &Ctx) -> bool {
+    lua::sim::run_once(ctx)
+}
+
+/// Render a focus for a version of the state in the context's world
+/// and print the resulting HTML.
+pub async fn render(ctx: Ctx, ver: u32, focus: String, param: Option<String>) -> bool {
+    let log = ctx.log.clone();
+    let world = ctx.world_name().to_string();
+    let (mut backends, app_state) = AppState::offline(ctx);
+    let world = match app_state.world_named(&world) {
+        Some(world) => world,
+        None => {
+            log.err(format_args!("there is no world named '{}'", world));
+            return false
+        }
+    };
+    let resp = tokio::select! {
+        resp = world.lua.render(Version::from(ver), focus, param) => resp,
+        _ = lua::run_all(&mut backends, &app_state) => None,
+    };
+    let resp = match resp {
+        Some(resp) => resp,
+        None => {
+            log.err("backend is not running");
+            return false
+        }
+    };
+
+    let status = resp.status();
+    if !status.is_success() {
+        log.of(Subsystem::Render).err(format_args!("render failed with status {}", status));
+        return false
+    }
+    let body = match hyper::body::to_bytes(resp.into_body()).await {
+        Ok(body) => body,
+        Err(e) => {
+            log.err(format_args!("could not read page: {}", e));
+            return false
+        }
+    };
+    if let Err(e) = io::stdout().write_all(&body) {
+        log.err(format_args!("could not write page: {}", e));
+        return false
+    }
+    true
+}
+
+/// Read a version of the state as JSON.
+fn read_state(ctx: &Ctx, ver: Version) -> Option<JV> {
+    let log = ctx.log.with(ctx.fields(Subsystem::Render).version(ver.as_usize()));
+    let path = ver.path(ctx);
+    let res = File::open(&path)
+        .and_then(|mut file| conv::bytes_to_msgpack(&mut file))
+        .and_then(conv::msgpack_to_json);
+    match res {
+        Ok(json) => Some(json),
+        Err(e) => {
+            log.err(format_args!("could not read '{}': {}", path.display(), e));
+            None
+        }
+    }
+}
+
+/// Print a version of the state as JSON.
+pub fn state_show(ctx: &Ctx, ver: u32) -> bool {
+    let json = match read_state(ctx, Version::from(ver)) {
+        Some(json) => json,
+        None => return false,
+    };
+    match serde_json::to_string_pretty(&json) {
+        Ok(s) => {
+            println!("{}", s);
+            true
+        }
+        Err(e) => {
+            ctx.log.err(format_args!("could not serialize state: {}", e));
+            false
+        }
+    }
+}
+
+/// Write JSON (read from `input`, or stdin if it is `None`) to a new state file.
+pub fn state_convert(ctx: &Ctx, ver: u32, input: Option<&Path>) -> bool {
+    let ver = Version::from(ver);
+    let log = ctx.log.with(ctx.fields(Subsystem::Sim).version(ver.as_usize()));
+    let path = ver.path(ctx);
+    if path.exists() {
+        log.err(format_args!("'{}' already exists", path.display()));
+        return false
+    }
+
+    let mut contents = String::new();
+    let res = match input {
+        Some(input) => fs::read_to_string(input).map(|s| contents = s),
+        None => io::stdin().read_to_string(&mut contents).map(drop),
+    };
+    if let Err(e) = res {
+        log.err(format_args!("could not read JSON: {}", e));
+        return false
+    }
+    let json = match serde_json::from_str(&contents) {
+        Ok(json) => json,
+        Err(e) => {
+            log.err(format_args!("could not parse JSON: {}", e));
+            return false
+        }
+    };
+
+    let state = match conv::json_to_msgpack(json) {
+        Ok(state) => state,
+        Err(e) => {
+            log.err(format_args!("could not convert JSON: {}", e));
+            return false
+        }
+    };
+    let res = File::create(&path).and_then(|mut file| conv::msgpack_to_bytes(&mut file, &state));
+    match res {
+        Ok(()) => {
+            log.info(format_args!("wrote new state file '{}'", path.display()));
+            true
+        }
+        Err(e) => {
+            log.err(format_args!("could not write state to file '{}': {}", path.display(), e));
+            false
+        }
+    }
+}
+
+/// Print the differences between a version of the state and another one
+/// (by default the previous one).
+pub fn state_diff(ctx: &Ctx, ver: u32, other: Option<u32>) -> bool {
+    let ver = Version::from(ver);
+    let other = match other.map(Version::from).or_else(|| ver.previous()) {
+        Some(other) => other,
+        None => {
+            ctx.log.err("version 0 has no previous version to compare against");
+            return false
+        }
+    };
+    let (old, new) = match (read_state(ctx, other), read_state(ctx, ver)) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return false,
+    };
+    let mut lines = Vec::new();
+    diff(String::new(), &old, &new, &mut lines);
+    for line in lines {
+        println!("{}", line);
+    }
+    true
+}
+
+/// Describe the differences between two JSON values, one line per changed
+/// value. Each line starts with `+` (added), `-` (removed) or `~` (changed).
+fn diff(path: String, old: &JV, new: &JV, out: &mut Vec<String>) {
+    let field = |key: &str| {
+        if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+    };
+    match (old, new) {
+        (JV::Object(old), JV::Object(new)) => {
+            for (key, old_value) in old {
+                match new.get(key) {
+                    Some(new_value) => diff(field(key), old_value, new_value, out),
+                    None => out.push(format!("- {}: {}", field(key), old_value)),
+                }
+            }
+            for (key, new_value) in new {
+                if !old.contains_key(key) {
+                    out.push(format!("+ {}: {}", field(key), new_value));
+                }
+            }
+        }
+        (JV::Array(old), JV::Array(new)) => {
+            for i in 0..old.len().max(new.len()) {
+                let index = format!("{}[{}]", path, i);
+                match (old.get(i), new.get(i)) {
+                    (Some(old), Some(new)) => diff(index, old, new, out),
+                    (Some(old), None) => out.push(format!("- {}: {}", index, old)),
+                    (None, Some(new)) => out.push(format!("+ {}: {}", index, new)),
+                    (None, None) => {}
+                }
+            }
+        }
+        (old, new) if old != new => out.push(format!("~ {}: {} -> {}", path, old, new)),
+        _ => {}
+    }
+}
@@ -0,0 +1,146 @@
+//! Reloads the config file while the server is running, applying the changes
+//! that can be made safely and reporting the ones that need a restart.
+
+use std::fs;
+use std::time::SystemTime;
+
+use super::alerts;
+use super::ctx::Cfg;
+
+/// How frequently (in seconds) is the config file checked for modifications?
+pub const WATCH_INTERVAL: u32 = 5;
+
+/// Return when the config file was last modified, if this can be determined.
+pub fn modified(cfg: &Cfg) -> Option<SystemTime> {
+    fs::metadata(&cfg.path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Return the dotted path of the renderer directory of each world, in order.
+fn render_keys(cfg: &Cfg) -> Vec<String> {
+    std::iter::once(String::from("paths.render"))
+        .chain((0..cfg.worlds.len()).map(|i| format!("worlds.{}.render", i)))
+        .collect()
+}
+
+impl super::AppState {
+    /// Reload the config file if it has been modified since it was last loaded.
+    pub(super) async fn watch_config(&self) {
+        let modified = modified(&self.ctx.cfg());
+        if modified.is_some() && modified != *self.cfg_modified.read() {
+            self.ctx.log.info("config file was modified; reloading it");
+            self.reload_config().await;
+        }
+    }
+
+    /// Reload the config file and apply the changes that don't require a restart.
+    /// Return the number of fields that changed, or `None` if the file couldn't be loaded.
+    pub(super) async fn reload_config(&self) -> Option<usize> {
+        let log = &self.ctx.log;
+        let guard = self.runtime_lock.lock();
+        let old = self.ctx.cfg();
+        *self.cfg_modified.write() = modified(&old);
+        let mut new = match Cfg::load(log, &old.path) {
+            Some(cfg) => cfg,
+            None => {
+                log.err("the config file could not be reloaded");
+                return None
+            }
+        };
+
+        /// Keep the old value of a field that only takes effect when the server starts.
+        macro_rules! keep {
+            ($name:expr => $($field:tt)+) => {
+                if new.$($field)+ != old.$($field)+ {
+                    log.err(format_args!(
+                        "'{}' was changed in the config file, but this requires a restart",
+                        $name,
+                    ));
+                    new.$($field)+ = old.$($field)+.to_owned();
+                }
+            }
+        }
+        keep!("addr" => addr);
+        keep!("network.admin-addr" => network.admin_addr);
+        keep!("paths.users" => paths.users);
+        keep!("paths.api-tokens" => paths.api_tokens);
+        keep!("paths.totp" => paths.totp);
+        keep!("paths.salt-key" => paths.salt_key);
+        keep!("log.file" => log.file);
+        keep!("log.max-size" => log.max_size);
+        keep!("log.max-age" => log.max_age);
+        keep!("log.retain" => log.retain);
+
+        // The listeners are set up when the server starts, so TLS can't be
+        // turned on or off, though the certificate can be changed.
+        match (&old.tls, &mut new.tls) {
+            (Some(old_tls), Some(new_tls)) => {
+                if new_tls.redirect_addr != old_tls.redirect_addr {
+                    log.err("'tls.redirect-addr' was changed in the config file, \
+                        but this requires a restart");
+                    new_tls.redirect_addr = old_tls.redirect_addr;
+                }
+            }
+            (None, None) => {}
+            _ => {
+                log.err("'tls' was added to or removed from the config file, \
+                    but this requires a restart");
+                new.tls = old.tls.clone();
+            }
+        }
+
+        // Each world has its own tasks, so worlds can't be added, removed or
+        // reordered, though their paths, prefixes and hosts can be changed.
+        let names = |cfg: &Cfg| cfg.worlds.iter().map(|w| w.name.clone()).collect::<Vec<_>>();
+        if names(&new) != names(&old) {
+            log.err("the list of worlds was changed in the config file, \
+                but this requires a restart");
+            new.worlds = old.worlds.clone();
+        }
+
+        let changes = old.changes(&new, log);
+        for change in &changes {
+            log.info(format_args!(
+                "config: '{}' changed from {} to {}",
+                change.key,
+                change.old.as_deref().unwrap_or("(none)"),
+                change.new.as_deref().unwrap_or("(none)"),
+            ));
+        }
+        let changed = |prefix: &str| changes.iter().any(|change| change.key.starts_with(prefix));
+
+        self.ctx.set_cfg(new);
+        drop(guard);
+        let new = self.ctx.cfg();
+
+        // Most fields are read from the config whenever they are used, but
+        // some things have to be reloaded explicitly.
+        log.configure(&new.log);
+        if changed("alerts") {
+            *self.alerts.write() = alerts::init(&new.alerts);
+        }
+        if changed("paths.blog") {
+            self.ctx.reload_blog();
+        }
+        if changed("tls.cert") || changed("tls.key") {
+            self.load_tls();
+        }
+        if changed("paths.templates") || changed("paths.blog") {
+            self.reload_templates();
+        }
+        for (world, key) in self.worlds.iter().zip(render_keys(&new)) {
+            if changed(&key) {
+                if !changed("paths.templates") && !changed("paths.blog") {
+                    world.reload_templates();
+                }
+                world.lua.reload_focuses(&world.ctx).await;
+            }
+        }
+
+        log.info(format_args!(
+            "reloaded config with {} change{}",
+            changes.len(),
+            if changes.len() == 1 { "" } else { "s" },
+        ));
+        Some(changes.len())
+    }
+}
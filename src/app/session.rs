@@ -0,0 +1,318 @@
+//! Utilities for issuing and checking the session cookies that
+//! grant access to the admin panel.
+
+use chrono::{DateTime, Utc};
+use hyper::{Response, Body, HeaderMap};
+use serde::Serialize;
+use tokio::time::{Instant, Duration};
+
+use std::net::IpAddr;
+
+use super::{Result, utils};
+use super::ctx::users::{Role, User};
+use super::ctx::log::Subsystem;
+
+/// The name of the cookie used to store the session.
+const COOKIE_NAME: &str = "session";
+
+/// The name of the header in which admin POST requests must include
+/// the CSRF token of their session.
+const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// How stale (in seconds) may the time a session was last seen get before it is
+/// updated? This lets most requests check their session without a write lock.
+const LAST_SEEN_RESOLUTION: i64 = 5;
+
+/// Describes the admin on whose behalf a request is being made.
+#[derive(Clone)]
+pub struct Admin {
+    /// The name of the admin's account.
+    pub name: String,
+    /// What the admin is allowed to do.
+    pub role: Role,
+    /// The ID of the session that the request was made with.
+    session: Option<u64>,
+}
+
+impl Admin {
+    /// Describe the holder of a particular account.
+    pub fn new(user: &User) -> Self {
+        Self {
+            name: user.name.clone(),
+            role: user.role,
+            session: None,
+        }
+    }
+
+    /// Describe the holder of an API token.
+    pub fn for_api_token(name: &str, scope: Role) -> Self {
+        Self {
+            name: format!("token:{}", name),
+            role: scope,
+            session: None,
+        }
+    }
+}
+
+/// Tracks information about a single logged-in session.
+pub struct Session {
+    /// Who logged in to create this session?
+    admin: Admin,
+    /// When does this session stop being valid?
+    expires: Instant,
+    /// A secret that must accompany every admin POST request made with this
+    /// session, so that other sites cannot forge them.
+    csrf_token: String,
+    /// When was the session created?
+    created: DateTime<Utc>,
+    /// When was the session last used to make a request?
+    last_seen: DateTime<Utc>,
+    /// The address of the client that logged in.
+    ip: IpAddr,
+}
+
+/// Describes how sessions are serialized when passing them to Tera.
+#[derive(Serialize)]
+pub struct TeraSession {
+    /// The ID of the session.
+    id: String,
+    /// The name of the admin who logged in.
+    user: String,
+    /// When was the session created?
+    created: DateTime<Utc>,
+    /// When was the session last used to make a request?
+    last_seen: DateTime<Utc>,
+    /// The address of the client that logged in.
+    ip: String,
+    /// Is this the session that is viewing the list?
+    is_current: bool,
+}
+
+impl super::AppState {
+    /// Return a `Duration` representing the period of time after which a
+    /// session is no longer considered valid.
+    fn get_session_age(&self) -> Duration {
+        Duration::from_secs(self.ctx.cfg().security.session_timeout as u64)
+    }
+
+    /// Compute the signature attached to a session cookie.
+    fn sign_session(&self, payload: &str) -> String {
+        utils::hmac_sha256(&self.session_key, payload)
+    }
+
+    /// Create a new session for `admin`, logged in from `ip`, and return a response
+    /// that redirects to `uri` while setting the cookie that identifies it.
+    pub(super) fn start_session(&self, mut admin: Admin, ip: IpAddr, uri: &str) -> Response<Body> {
+        let id: u64 = rand::random();
+        admin.session = Some(id);
+        let age = self.get_session_age();
+        let now = Utc::now();
+        let expires_at = now.timestamp() + age.as_secs() as i64;
+
+        self.sessions.write().insert(id, Session {
+            admin,
+            expires: Instant::now() + age,
+            csrf_token: hex::encode(rand::random::<[u8; 16]>()),
+            created: now,
+            last_seen: now,
+            ip,
+        });
+
+        let payload = format!("{}.{}", id, expires_at);
+        let cookie = format!(
+            "{}={}.{}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}",
+            COOKIE_NAME,
+            payload,
+            self.sign_session(&payload),
+            age.as_secs(),
+            self.cookie_secure_attr(),
+        );
+
+        let mut response = Self::redirect(uri);
+        response.headers_mut().insert(
+            hyper::header::SET_COOKIE,
+            cookie.parse().expect("cookie is a valid header value"),
+        );
+        response
+    }
+
+    /// End the session that the request was made with (if any), and return a
+    /// response that redirects to `uri` while clearing the session cookie.
+    pub(super) fn end_session(&self, headers: &HeaderMap, uri: &str) -> Response<Body> {
+        if let Some(Admin { name, session: Some(id), .. }) = self.authenticate(headers) {
+            self.sessions.write().remove(&id);
+            self.ctx.log.of(Subsystem::Auth).info(format_args!("user '{}' logged out", name));
+        }
+
+        let cookie = format!(
+            "{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict{}",
+            COOKIE_NAME,
+            self.cookie_secure_attr(),
+        );
+        let mut response = Self::redirect(uri);
+        response.headers_mut().insert(
+            hyper::header::SET_COOKIE,
+            cookie.parse().expect("cookie is a valid header value"),
+        );
+        response
+    }
+
+    /// Return the attribute that stops browsers from sending the session cookie
+    /// over plain HTTP, if HTTPS is being served.
+    fn cookie_secure_attr(&self) -> &'static str {
+        if self.ctx.cfg().tls.is_some() { "; Secure" } else { "" }
+    }
+
+    /// Remove any sessions that have expired.
+    pub(super) fn clear_sessions(&self) {
+        let mut sessions = self.sessions.write();
+        let num_sessions = sessions.len();
+        sessions.retain(|_, session| session.expires > Instant::now());
+        let num_cleared = num_sessions - sessions.len();
+        if num_cleared > 0 {
+            self.ctx.log.of(Subsystem::Auth).status(format!(
+                "cleared {} expired session{}",
+                num_cleared,
+                if num_cleared == 1 { "" } else { "s" }
+            ))
+        }
+    }
+
+    /// Return all active sessions, most recently used first. The session
+    /// that `admin` is using is marked as current.
+    pub(super) fn list_sessions(&self, admin: &Admin) -> Vec<TeraSession> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.read()
+            .iter()
+            .filter(|(_, session)| session.expires > now)
+            .map(|(&id, session)| TeraSession {
+                id: id.to_string(),
+                user: session.admin.name.clone(),
+                created: session.created,
+                last_seen: session.last_seen,
+                ip: session.ip.to_string(),
+                is_current: admin.session == Some(id),
+            })
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.last_seen));
+        sessions
+    }
+
+    /// Revoke the session with the given ID, and return the name of the admin
+    /// who owned it (or `None` if it did not exist).
+    pub(super) fn revoke_session(&self, id: u64) -> Option<String> {
+        self.sessions.write().remove(&id).map(|session| session.admin.name)
+    }
+
+    /// Check the credentials sent with a request and return the admin who made it.
+    /// 
+    /// If the request has an `Authorization: Bearer` header, it must contain a valid
+    /// API token. Otherwise, the session cookie must have a valid signature and refer
+    /// to a session that has not expired.
+    pub(super) fn authenticate(&self, headers: &HeaderMap) -> Option<Admin> {
+        if let Some(auth) = utils::get_header(headers, hyper::header::AUTHORIZATION) {
+            let token = auth.strip_prefix("Bearer ")?;
+            return self.authenticate_api_token(token.trim());
+        }
+
+        let cookie = utils::get_cookie(headers, COOKIE_NAME)?;
+
+        let sig_start = cookie.rfind('.')?;
+        let (payload, sig) = (&cookie[..sig_start], &cookie[sig_start + 1..]);
+        if !utils::constant_time_eq(&self.sign_session(payload), sig) {
+            return None;
+        }
+
+        let mut parts = payload.splitn(2, '.');
+        let id: u64 = parts.next()?.parse().ok()?;
+        let expires_at: i64 = parts.next()?.parse().ok()?;
+        if expires_at <= chrono::Utc::now().timestamp() {
+            return None;
+        }
+
+        // Expired sessions are removed by `clear_sessions`.
+        let now = Utc::now();
+        let (admin, stale) = {
+            let sessions = self.sessions.read();
+            let session = sessions.get(&id).filter(|s| s.expires > Instant::now())?;
+            let stale = (now - session.last_seen).num_seconds() >= LAST_SEEN_RESOLUTION;
+            (session.admin.clone(), stale)
+        };
+        if stale {
+            if let Some(session) = self.sessions.write().get_mut(&id) {
+                session.last_seen = session.last_seen.max(now);
+            }
+        }
+        Some(admin)
+    }
+
+    /// Return the CSRF token that must accompany POST requests made by the admin.
+    pub(super) fn csrf_token(&self, admin: &Admin) -> Option<String> {
+        let id = admin.session?;
+        self.sessions.read().get(&id).map(|s| s.csrf_token.clone())
+    }
+
+    /// Return a 403 error unless a POST request made by the admin carries the CSRF
+    /// token of its session and was sent from a page served by this site.
+    /// 
+    /// Requests authenticated with an API token are exempt, since browsers never
+    /// attach those automatically.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn check_csrf(&self, admin: &Admin, headers: &HeaderMap) -> Result<()> {
+        if admin.session.is_none() {
+            return Ok(());
+        }
+
+        /// Remove the scheme and path from a URL, leaving only the host and port.
+        fn url_host(url: &str) -> Option<&str> {
+            let rest = &url[url.find("://")? + 3..];
+            Some(rest.split('/').next().unwrap_or(rest))
+        }
+
+        let host = utils::get_header(headers, hyper::header::HOST);
+        let source = utils::get_header(headers, hyper::header::ORIGIN)
+            .or_else(|| utils::get_header(headers, hyper::header::REFERER))
+            .and_then(url_host);
+        if host.is_none() || source != host {
+            self.ctx.log.of(Subsystem::Auth).info(format_args!(
+                "rejected request by '{}' from foreign origin {:?}",
+                admin.name,
+                source,
+            ));
+            return self.error_403();
+        }
+
+        let expected = self.csrf_token(admin);
+        match (expected, utils::get_header(headers, CSRF_HEADER)) {
+            (Some(expected), Some(given)) if utils::constant_time_eq(&expected, given) => Ok(()),
+            _ => {
+                self.ctx.log.of(Subsystem::Auth).info(format_args!(
+                    "rejected request by '{}' with missing or invalid CSRF token",
+                    admin.name,
+                ));
+                self.error_403()
+            }
+        }
+    }
+
+    /// Return a 403 error unless the request was made by logging in to an account
+    /// (as opposed to with an API token).
+    #[allow(clippy::result_large_err)]
+    pub(super) fn require_account(&self, admin: &Admin) -> Result<()> {
+        if admin.session.is_some() {
+            Ok(())
+        } else {
+            self.error_403()
+        }
+    }
+
+    /// Return a 403 error unless the admin is permitted to act with the given role.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn require(&self, admin: &Admin, role: Role) -> Result<()> {
+        if admin.role >= role {
+            Ok(())
+        } else {
+            self.error_403()
+        }
+    }
+}
@@ -8,7 +8,9 @@ use std::path::{Path, PathBuf};
 
 use super::{Result, Ctx};
 use super::lua;
+use super::world::World;
 use super::utils::SourceChain;
+use super::ctx::log::{Fields, Subsystem};
 
 /// Contains data related to rendering templates.
 pub struct Templates {
@@ -19,26 +21,29 @@ pub struct Templates {
 }
 
 impl Templates {
-    /// Create a `Tera` instance containing all templates used by the application.
+    /// Create a `Tera` instance containing all templates used by the application,
+    /// including the renderer templates of the context's world.
     pub fn load(ctx: &Ctx) -> Self {
         let mut tera = Tera::default();
         tera.autoescape_on(vec![".html.tera"]);
 
         let mut len = 0;
+        let cfg = ctx.cfg();
     
         let mut base_path: Cow<Path>;
     
         macro_rules! register {
             ($name:expr => $path:expr) => {{
                 if let Err(e) = tera.add_template_file(base_path.join($path), Some($name)) {
-                    ctx.log.err(format_args!("tera:\n{}", SourceChain(e)));
+                    ctx.log.with(ctx.fields(Subsystem::Templates))
+                        .err(format_args!("tera:\n{}", SourceChain(e)));
                 } else {
                     len += 1;
                 }
             }}
         }
     
-        base_path = (&*ctx.cfg.paths.templates).into();
+        base_path = (&*cfg.paths.templates).into();
         
         // Generic parent, defining structure for all pages
         register!("base.html" => "base.html.tera");
@@ -51,14 +56,20 @@ impl Templates {
         // Error messages
         register!("400.html" => "error/400.html.tera");
         register!("401.html" => "error/401.html.tera");
+        register!("403.html" => "error/403.html.tera");
         register!("404.html" => "error/404.html.tera");
         register!("404_no_state.html" => "error/404_no_state.html.tera");
+        register!("429.html" => "error/429.html.tera");
         register!("500.html" => "error/500.html.tera");
     
         // Pages accessible only to admins
         register!("admin/index.html" => "admin/index.html.tera");
         register!("admin/filtered_log.html" => "admin/filtered_log.html.tera");
         register!("admin/sim_files.html" => "admin/sim_files.html.tera");
+        register!("admin/audit.html" => "admin/audit.html.tera");
+        register!("admin/tokens.html" => "admin/tokens.html.tera");
+        register!("admin/sessions.html" => "admin/sessions.html.tera");
+        register!("admin/totp.html" => "admin/totp.html.tera");
 
         // Blog posts
         register!("blog_base.html" => "blog_base.html.tera");
@@ -66,7 +77,7 @@ impl Templates {
             register!(&format!("blog/{}.html", id) => format!("blog/{}.html.tera", id));
         }
     
-        base_path = (&*ctx.cfg.paths.render).into();
+        base_path = ctx.world(&cfg).render.into();
     
         // Generic parent for all pages in the renderer
         register!("format_base.html" => "format_base.html.tera");
@@ -80,20 +91,27 @@ impl Templates {
     
         Self { tera, len }
     }
+
+    /// Return the number of templates that were loaded.
+    pub fn count(&self) -> usize {
+        self.len
+    }
 }
 
 impl super::AppState {
-    /// Render a Tera template with the provided context.
+    /// Render a Tera template from a world with the provided context.
     /// 
     /// If `expect_present` is true, treat a missing template error as 500.
     /// If not, treat it as a 404.
+    #[allow(clippy::result_large_err)]
     fn render_with_config(
         &self,
+        world: &World,
         name: &str,
         ctx: &tera::Context,
         expect_present: bool,
     ) -> Result<Response<Body>> {
-        let templates = self.templates.read();
+        let templates = world.templates.read();
         match templates.tera.render(name, ctx) {
             Ok(body) => {
                 let mime = mime_guess::from_path(name).first_or_octet_stream();
@@ -106,19 +124,20 @@ impl super::AppState {
             Err(e) => {
                 if !expect_present && matches!(e.kind, tera::ErrorKind::TemplateNotFound(_)) {
                     if name == "404.html" {
-                        self.ctx.log.err("recursive 404");
+                        self.ctx.log.of(Subsystem::Templates).err("recursive 404");
                         Self::text_error(404, "404: the 404 page was not found")
                     } else {
                         self.error_404()
                     }
                 } else {
                     if name == "500.html" {
-                        self.ctx.log.err("recursive 500");
+                        self.ctx.log.of(Subsystem::Templates).err("recursive 500");
                         Self::text_error(500,
                             "500: while attempting to handle the error, \
                              the server encountered an error")
                     } else {
-                        self.error_500(format_args!("tera:\n{}", SourceChain(e)))
+                        self.error_500_with(Fields::new(Subsystem::Templates),
+                            format_args!("tera:\n{}", SourceChain(e)))
                     }
                 }
             }
@@ -127,24 +146,36 @@ impl super::AppState {
 
     /// Render a Tera template with the provided context. If the provided template does not
     /// exist, return a 500 error.
+    #[allow(clippy::result_large_err)]
     pub(super) fn render(&self, name: &str, ctx: &tera::Context) -> Result<Response<Body>> {
-        self.render_with_config(name, ctx, true)
+        self.render_with_config(self.main_world(), name, ctx, true)
     }
 
     /// Render a Tera template with the provided context. If the provided template does not
     /// exist, return a 404 error.
+    #[allow(clippy::result_large_err)]
     pub(super) fn try_render(&self, name: &str, ctx: &tera::Context) -> Result<Response<Body>> {
-        self.render_with_config(name, ctx, false)
+        self.render_with_config(self.main_world(), name, ctx, false)
     }
 
-    /// Replace the current `Tera` instance with a new one based on the current
-    /// version of the template files.
-    pub(super) fn reload_templates(&self) {
-        *self.templates.write() = Templates::load(&self.ctx);
+    /// Render a template belonging to a particular world (such as one of its
+    /// renderer templates) with the provided context. If the provided template
+    /// does not exist, return a 500 error.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn render_in(
+        &self,
+        world: &World,
+        name: &str,
+        ctx: &tera::Context,
+    ) -> Result<Response<Body>> {
+        self.render_with_config(world, name, ctx, true)
     }
 
-    /// Return the number of templates that are currently loaded.
-    pub(super) fn num_templates(&self) -> usize {
-        self.templates.read().len
+    /// Replace the `Tera` instance of every world with a new one based on the
+    /// current version of the template files.
+    pub(super) fn reload_templates(&self) {
+        for world in &self.worlds {
+            world.reload_templates();
+        }
     }
 }
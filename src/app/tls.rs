@@ -0,0 +1,245 @@
+//! Terminates HTTPS using the certificate and key named in the `[tls]` section
+//! of the config, and redirects plain HTTP requests to HTTPS.
+
+use chrono::{DateTime, Utc};
+use hyper::{Request, Response, Body};
+use serde::Serialize;
+use tokio_rustls::rustls::{NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::rustls::internal::pemfile;
+
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::hyper_boilerplate::Listener;
+use super::{Result, utils};
+use super::ctx::cfg::{Cfg, Tls};
+use super::ctx::log::Subsystem;
+
+/// The certificate that is currently used to accept connections.
+pub struct Loaded {
+    /// The configuration that new connections are accepted with.
+    config: Arc<ServerConfig>,
+    /// The file that the certificate chain was read from.
+    cert: PathBuf,
+    /// When was the certificate loaded?
+    time: DateTime<Utc>,
+}
+
+/// Describes how the loaded certificate is serialized when passing it to Tera.
+#[derive(Serialize)]
+pub struct TeraTls {
+    /// The file that the certificate chain was read from.
+    cert: String,
+    /// When was the certificate loaded?
+    loaded: String,
+    /// The address from which plain HTTP requests are redirected, if any.
+    redirect_addr: Option<String>,
+}
+
+/// Read a PEM file.
+fn read_pem(path: &Path) -> std::result::Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("could not read '{}': {}", path.display(), e))
+}
+
+/// Read the private key from a PEM file, which may be in PKCS #8 or RSA format.
+fn read_key(path: &Path) -> std::result::Result<PrivateKey, String> {
+    let pem = read_pem(path)?;
+    let keys = match pemfile::pkcs8_private_keys(&mut pem.as_slice()) {
+        Ok(keys) if keys.is_empty() => pemfile::rsa_private_keys(&mut pem.as_slice()),
+        res => res,
+    };
+    keys.map_err(|()| format!("'{}' is not a valid PEM file", path.display()))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("'{}' does not contain a private key", path.display()))
+}
+
+/// Build the configuration that connections are accepted with from
+/// the certificate chain and key named in the config.
+pub fn load_config(tls: &Tls) -> std::result::Result<ServerConfig, String> {
+    let certs = pemfile::certs(&mut read_pem(&tls.cert)?.as_slice())
+        .map_err(|()| format!("'{}' is not a valid PEM file", tls.cert.display()))?;
+    if certs.is_empty() {
+        return Err(format!("'{}' does not contain a certificate", tls.cert.display()))
+    }
+    let key = read_key(&tls.key)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(certs, key)
+        .map_err(|e| format!("could not use '{}': {}", tls.key.display(), e))?;
+    Ok(config)
+}
+
+/// Return whether a name in `tls.hosts` could appear in a `Host` header:
+/// it can't be empty or include a scheme, port, path or whitespace.
+pub fn is_valid_host(host: &str) -> bool {
+    !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+/// Return whether a `Host` header (without the port) names this server:
+/// the IP address it listens on (or `localhost`, if that is a loopback
+/// address), one of `tls.hosts`, or the host of one of the worlds.
+fn is_known_host(cfg: &Cfg, host: &str) -> bool {
+    let ip = cfg.addr.ip();
+    let matches_ip = match ip {
+        IpAddr::V4(ip) => host == ip.to_string(),
+        IpAddr::V6(ip) => host == format!("[{}]", ip),
+    };
+    let hosts = cfg.tls.iter().flat_map(|tls| &tls.hosts);
+    matches_ip
+        || (ip.is_loopback() && host.eq_ignore_ascii_case("localhost"))
+        || hosts.into_iter().any(|name| name.eq_ignore_ascii_case(host))
+        || cfg.worlds.iter().any(|world| world.host.as_deref() == Some(host))
+}
+
+/// Return the HTTPS URL that a plain HTTP request for `path` on `host` (the
+/// `Host` header, which may include a port) is redirected to, or `None` if
+/// the host isn't this server's.
+fn redirect_location(cfg: &Cfg, host: &str, path: &str) -> Option<String> {
+    let host = utils::host_without_port(host);
+    if !is_known_host(cfg, host) {
+        return None
+    }
+    let port = match cfg.addr.port() {
+        443 => String::new(),
+        port => format!(":{}", port),
+    };
+    Some(format!("https://{}{}{}", host, port, path))
+}
+
+impl super::AppState {
+    /// Load the certificate and key named in the config, so that new connections
+    /// use them. If they can't be loaded, the ones that were in use are kept.
+    /// Return whether this succeeded (or TLS is disabled).
+    pub fn load_tls(&self) -> bool {
+        let cfg = self.ctx.cfg();
+        let tls = match &cfg.tls {
+            Some(tls) => tls,
+            None => return true,
+        };
+        let log = self.ctx.log.of(Subsystem::Http);
+        match load_config(tls) {
+            Ok(config) => {
+                *self.tls.write() = Some(Loaded {
+                    config: Arc::new(config),
+                    cert: tls.cert.clone(),
+                    time: Utc::now(),
+                });
+                log.info(format_args!("loaded TLS certificate from '{}'", tls.cert.display()));
+                true
+            }
+            Err(e) => {
+                log.err(format_args!("could not load TLS certificate: {}", e));
+                false
+            }
+        }
+    }
+
+    /// Return the configuration that new connections to `listener` are
+    /// accepted with, or `None` if they use plain HTTP.
+    pub(super) fn tls_config_for(&self, listener: Listener) -> Option<Arc<ServerConfig>> {
+        if listener == Listener::Redirect {
+            return None
+        }
+        self.tls.read().as_ref().map(|loaded| Arc::clone(&loaded.config))
+    }
+
+    /// Return when the current certificate was loaded, if there is one.
+    pub(super) fn tls_loaded_time(&self) -> Option<DateTime<Utc>> {
+        self.tls.read().as_ref().map(|loaded| loaded.time)
+    }
+
+    /// Describe the loaded certificate for the admin panel.
+    pub(super) fn tls_status(&self) -> Option<TeraTls> {
+        let redirect_addr = self.ctx.cfg().tls.as_ref()
+            .and_then(|tls| tls.redirect_addr)
+            .map(|addr| addr.to_string());
+        self.tls.read().as_ref().map(|loaded| TeraTls {
+            cert: loaded.cert.display().to_string(),
+            loaded: loaded.time.to_rfc3339(),
+            redirect_addr,
+        })
+    }
+
+    /// Generate a response that sends a plain HTTP request to the same
+    /// path on the HTTPS server. Requests for hosts that aren't this
+    /// server's are rejected rather than redirected elsewhere.
+    #[allow(clippy::result_large_err)]
+    pub(super) fn redirect_to_https(&self, req: &Request<Body>) -> Result<Response<Body>> {
+        let path = req.uri().path_and_query().map_or("/", |pnq| pnq.as_str());
+        let location = utils::get_header(req.headers(), hyper::header::HOST)
+            .and_then(|host| redirect_location(&self.ctx.cfg(), host, path));
+        let location = match location {
+            Some(location) => location,
+            None => return self.error_400(),
+        };
+        Response::builder()
+            .status(301)
+            .header("Location", location)
+            .body(Body::empty())
+            .or_else(|_| self.error_400())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load the example config, serving HTTPS at `addr` with the given host names.
+    fn https_cfg(addr: &str, hosts: &[&str]) -> Cfg {
+        let mut cfg: Cfg = toml::from_str(include_str!("../../example/Config.toml")).unwrap();
+        cfg.addr = addr.parse().unwrap();
+        cfg.tls = Some(Tls {
+            cert: PathBuf::from("cert.pem"),
+            key: PathBuf::from("key.pem"),
+            redirect_addr: Some("0.0.0.0:80".parse().unwrap()),
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+        });
+        cfg
+    }
+
+    #[test]
+    fn redirects_configured_names() {
+        let cfg = https_cfg("0.0.0.0:443", &["example.com"]);
+        assert_eq!(
+            redirect_location(&cfg, "example.com", "/5/people?id=3").as_deref(),
+            Some("https://example.com/5/people?id=3"),
+        );
+        assert_eq!(
+            redirect_location(&cfg, "Example.COM:80", "/").as_deref(),
+            Some("https://Example.COM/"),
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_names() {
+        let cfg = https_cfg("0.0.0.0:443", &["example.com"]);
+        assert_eq!(redirect_location(&cfg, "evil.com", "/"), None);
+        assert_eq!(redirect_location(&cfg, "example.com.evil.com", "/"), None);
+        assert_eq!(redirect_location(&cfg, "localhost", "/"), None);
+    }
+
+    #[test]
+    fn redirects_addresses() {
+        let cfg = https_cfg("127.0.0.1:3000", &[]);
+        assert_eq!(
+            redirect_location(&cfg, "127.0.0.1:3080", "/blog").as_deref(),
+            Some("https://127.0.0.1:3000/blog"),
+        );
+        assert_eq!(
+            redirect_location(&cfg, "localhost", "/").as_deref(),
+            Some("https://localhost:3000/"),
+        );
+        let cfg = https_cfg("[::1]:443", &[]);
+        assert_eq!(redirect_location(&cfg, "[::1]", "/").as_deref(), Some("https://[::1]/"));
+    }
+
+    #[test]
+    fn validates_host_names() {
+        assert!(is_valid_host("www.example.com"));
+        assert!(!is_valid_host(""));
+        assert!(!is_valid_host("example.com:443"));
+        assert!(!is_valid_host("https://example.com"));
+    }
+}
@@ -0,0 +1,353 @@
+//! Implements RFC 6238 time-based one-time passwords (TOTP), which admins can
+//! enroll in as a second factor for logging in.
+//!
+//! Enrollments are stored in a JSON file on disk. The functions that generate
+//! and check codes take the current time as a parameter, so they do not depend
+//! on the system clock.
+
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use sha1::Sha1;
+
+use std::collections::HashMap;
+use std::fs;
+
+use super::{Ctx, utils};
+use super::ctx::log::Subsystem;
+
+/// The number of seconds for which each code is valid.
+const STEP: u64 = 30;
+
+/// The number of digits in each code.
+const DIGITS: u32 = 6;
+
+/// The number of steps before or after the current one whose codes are also
+/// accepted, to allow for clock drift.
+const SKEW: u64 = 1;
+
+/// The number of recovery codes generated for each enrollment.
+const NUM_RECOVERY_CODES: usize = 8;
+
+/// The name shown in authenticator apps.
+const ISSUER: &str = "Nokevair";
+
+/// Tracks the second factor of a single account.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Enrollment {
+    /// The shared secret, encoded as base32.
+    secret: String,
+    /// The SHA256 hashes of the recovery codes that have not yet been used.
+    recovery_hashes: Vec<String>,
+    /// The time step of the last code that was accepted. Codes from this step
+    /// or earlier ones are rejected, so that each code can only be used once.
+    #[serde(default)]
+    last_step: Option<u64>,
+}
+
+/// Describes the information that is shown to an admin once, when they enroll.
+#[derive(Serialize)]
+pub struct EnrollmentInfo {
+    /// The shared secret, encoded as base32.
+    secret: String,
+    /// A URI that authenticator apps can import (usually via a QR code).
+    uri: String,
+    /// Codes that can each be used once in place of a TOTP code.
+    recovery_codes: Vec<String>,
+}
+
+/// Encode bytes as unpadded RFC 4648 base32.
+fn base32_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::new();
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for &b in bytes {
+        buffer = (buffer << 8) | b as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+/// Decode unpadded RFC 4648 base32, or return `None` if it is malformed.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let val = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | val as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Compute the code for the given secret at the given Unix time.
+pub fn code_at(secret: &[u8], unix_time: u64) -> u32 {
+    let counter = unix_time / STEP;
+    let mut mac = Hmac::<Sha1>::new_varkey(secret)
+        .expect("HMAC accepts keys of any length");
+    mac.input(&counter.to_be_bytes());
+    let hash = mac.result().code();
+
+    // Dynamic truncation, as described in RFC 4226.
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let bin = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    bin % 10u32.pow(DIGITS)
+}
+
+/// Check whether `code` is valid for the given secret at the given Unix time.
+/// Return the time step that it belongs to if it is.
+pub fn verify(secret: &[u8], code: &str, unix_time: u64) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let step = unix_time / STEP;
+    (step.saturating_sub(SKEW)..=step + SKEW).find(|s| code_at(secret, s * STEP) == code)
+}
+
+impl Enrollment {
+    /// Generate a new secret and set of recovery codes for the given user.
+    pub fn generate(user: &str) -> (Self, EnrollmentInfo) {
+        let secret = base32_encode(&rand::random::<[u8; 20]>());
+        let recovery_codes = (0..NUM_RECOVERY_CODES)
+            .map(|_| {
+                let code = hex::encode(rand::random::<[u8; 5]>());
+                format!("{}-{}", &code[..5], &code[5..])
+            })
+            .collect::<Vec<_>>();
+        let issuer = utils::percent_encode(ISSUER);
+        let uri = format!(
+            "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&digits={digits}&period={step}",
+            issuer = issuer,
+            user = utils::percent_encode(user),
+            secret = secret,
+            digits = DIGITS,
+            step = STEP,
+        );
+        let enrollment = Self {
+            secret: secret.clone(),
+            recovery_hashes: recovery_codes.iter().map(|c| utils::sha256(c)).collect(),
+            last_step: None,
+        };
+        (enrollment, EnrollmentInfo { secret, uri, recovery_codes })
+    }
+
+    /// Check whether `code` is a valid TOTP code at the given Unix time that
+    /// is newer than the last one accepted. If it is, remember its time step
+    /// so that it can't be used again.
+    pub fn verify(&mut self, code: &str, unix_time: u64) -> bool {
+        let step = base32_decode(&self.secret)
+            .and_then(|secret| verify(&secret, code, unix_time))
+            .filter(|&step| self.last_step.is_none_or(|last| step > last));
+        if step.is_some() {
+            self.last_step = step;
+        }
+        step.is_some()
+    }
+
+    /// If `code` is an unused recovery code, mark it as used and return `true`.
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let hash = utils::sha256(code.trim());
+        let len = self.recovery_hashes.len();
+        self.recovery_hashes.retain(|h| !utils::constant_time_eq(h, &hash));
+        self.recovery_hashes.len() != len
+    }
+}
+
+/// Read the TOTP enrollments from the file named in the config. If it doesn't
+/// exist yet, nobody is enrolled.
+pub fn load(ctx: &Ctx) -> HashMap<String, Enrollment> {
+    let path = &ctx.cfg().paths.totp;
+    let contents = match fs::read(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            ctx.log.of(Subsystem::Auth).err(format_args!("while reading TOTP enrollments: {}", e));
+            return HashMap::new()
+        }
+    };
+    match serde_json::from_slice(&contents) {
+        Ok(enrollments) => enrollments,
+        Err(e) => {
+            ctx.log.of(Subsystem::Auth).err(format_args!("while parsing TOTP enrollments: {}", e));
+            HashMap::new()
+        }
+    }
+}
+
+impl super::AppState {
+    /// Write the current set of TOTP enrollments to disk.
+    fn save_totp(&self, enrollments: &HashMap<String, Enrollment>) {
+        let path = &self.ctx.cfg().paths.totp;
+        let res = serde_json::to_vec_pretty(enrollments)
+            .map_err(std::io::Error::from)
+            .and_then(|json| fs::write(path, json));
+        if let Err(e) = res {
+            self.ctx.log.of(Subsystem::Auth).err(format_args!(
+                "could not write TOTP enrollments to '{}': {}",
+                path.display(),
+                e,
+            ));
+        }
+    }
+
+    /// Return whether the given user has enrolled in TOTP.
+    pub(super) fn has_totp(&self, user: &str) -> bool {
+        self.totp.read().contains_key(user)
+    }
+
+    /// Check the second factor provided when logging in as `user`. This is either
+    /// a TOTP code or an unused recovery code. Users who have not enrolled always pass.
+    pub(super) fn check_totp(&self, user: &str, code: Option<&str>) -> bool {
+        let mut enrollments = self.totp.write();
+        let enrollment = match enrollments.get_mut(user) {
+            Some(e) => e,
+            None => return true,
+        };
+        let code = match code {
+            Some(c) => c,
+            None => return false,
+        };
+        let now = chrono::Utc::now().timestamp() as u64;
+        if enrollment.verify(code, now) {
+            self.save_totp(&enrollments);
+            true
+        } else if enrollment.use_recovery_code(code) {
+            self.ctx.log.of(Subsystem::Auth).info(format_args!(
+                "user '{}' used a TOTP recovery code", user));
+            self.save_totp(&enrollments);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Start enrolling `user` in TOTP, and return the information they need to
+    /// set up their authenticator. The enrollment only takes effect once it is
+    /// confirmed with `confirm_totp`.
+    pub(super) fn begin_totp(&self, user: &str) -> EnrollmentInfo {
+        let (enrollment, info) = Enrollment::generate(user);
+        self.totp_pending.write().insert(user.to_string(), enrollment);
+        info
+    }
+
+    /// Finish enrolling `user` in TOTP if `code` is valid for the secret
+    /// generated by `begin_totp`. Return whether it was.
+    pub(super) fn confirm_totp(&self, user: &str, code: &str) -> bool {
+        let mut pending = self.totp_pending.write();
+        let now = chrono::Utc::now().timestamp() as u64;
+        if !pending.get_mut(user).is_some_and(|e| e.verify(code, now)) {
+            return false
+        }
+        let enrollment = pending.remove(user).expect("enrollment was just verified");
+        let mut enrollments = self.totp.write();
+        enrollments.insert(user.to_string(), enrollment);
+        self.save_totp(&enrollments);
+        true
+    }
+
+    /// Remove the TOTP enrollment of `user` if `code` is currently valid for it.
+    /// Return whether it was.
+    pub(super) fn disable_totp(&self, user: &str, code: &str) -> bool {
+        let mut enrollments = self.totp.write();
+        let now = chrono::Utc::now().timestamp() as u64;
+        if !enrollments.get_mut(user).is_some_and(|e| e.verify(code, now)) {
+            return false
+        }
+        enrollments.remove(user);
+        self.save_totp(&enrollments);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The secret used by the SHA-1 test vectors in RFC 6238.
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    /// Format a code as it would be entered.
+    fn fmt(code: u32) -> String {
+        format!("{:06}", code)
+    }
+
+    #[test]
+    fn rfc_6238_vectors() {
+        assert_eq!(fmt(code_at(SECRET, 59)), "287082");
+        assert_eq!(fmt(code_at(SECRET, 1111111109)), "081804");
+        assert_eq!(verify(SECRET, "287082", 59), Some(1));
+        assert_eq!(verify(SECRET, "081804", 1111111109), Some(1111111109 / STEP));
+    }
+
+    #[test]
+    fn skew_window_edges() {
+        let step = 1111111109 / STEP;
+        let code = fmt(code_at(SECRET, step * STEP));
+        let earliest = (step - SKEW) * STEP;
+        let latest = (step + SKEW + 1) * STEP - 1;
+        assert_eq!(verify(SECRET, &code, earliest - 1), None);
+        assert_eq!(verify(SECRET, &code, earliest), Some(step));
+        assert_eq!(verify(SECRET, &code, latest), Some(step));
+        assert_eq!(verify(SECRET, &code, latest + 1), None);
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        for code in &["", "28708", "0287082", "28708a", "+28708", "２８７０８２"] {
+            assert_eq!(verify(SECRET, code, 59), None, "{:?}", code);
+        }
+        assert_eq!(verify(SECRET, " 287082 ", 59), Some(1));
+    }
+
+    #[test]
+    fn codes_cannot_be_reused() {
+        let mut enrollment = Enrollment {
+            secret: base32_encode(SECRET),
+            recovery_hashes: Vec::new(),
+            last_step: None,
+        };
+        assert!(enrollment.verify("287082", 59));
+        assert!(!enrollment.verify("287082", 59));
+        assert!(!enrollment.verify("287082", 60));
+        // A code from a later step is still accepted.
+        assert!(enrollment.verify(&fmt(code_at(SECRET, 60)), 60));
+        assert!(!enrollment.verify("287082", 45));
+    }
+
+    #[test]
+    fn uri_encodes_user_names() {
+        let (_, info) = Enrollment::generate("ann smith:a?b&c");
+        let prefix = "otpauth://totp/Nokevair:ann%20smith%3Aa%3Fb%26c?secret=";
+        assert!(info.uri.starts_with(prefix), "unexpected URI '{}'", info.uri);
+        assert!(info.uri.contains("&issuer=Nokevair&"));
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        assert_eq!(base32_encode(SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_decode(&base32_encode(SECRET)).as_deref(), Some(SECRET));
+    }
+}
@@ -0,0 +1,173 @@
+//! Exposes the `World` type. A world is a renderer and a simulation along with
+//! the state files they share. The main world is described by the `[runtime]`
+//! section and `[paths]` of the config; `[[worlds]]` sections describe others,
+//! which are served alongside it under their own path prefix or host.
+
+use hyper::HeaderMap;
+use parking_lot::RwLock;
+use serde::Serialize;
+
+use std::sync::atomic::Ordering;
+
+use super::{Ctx, utils};
+use super::ctx::cfg::{MAIN_WORLD, RuntimeValues};
+use super::lua::{self, sim::Sim};
+use super::templates::Templates;
+
+/// Holds the parts of the application state that belong to a single world.
+pub struct World {
+    /// The context of the world, which refers to its own directories.
+    pub(super) ctx: Ctx,
+    /// Contains data used to render templates, including the world's renderer templates.
+    pub(super) templates: RwLock<Templates>,
+    /// Permits interaction with the task running the world's Lua renderer instance.
+    pub(super) lua: lua::Frontend,
+    /// Permits interaction with the world's Lua simulation program.
+    pub(super) sim: Sim,
+}
+
+/// Describes how worlds are serialized when passing them to Tera.
+#[derive(Serialize)]
+pub struct TeraWorld {
+    /// The name of the world.
+    name: String,
+    /// Where the world is served from, e.g. `/fork` or `fork.example.com`.
+    mount: String,
+    /// The URL under which the admin routes for the world are found.
+    admin_url: String,
+    /// The number of focuses that the renderer has loaded.
+    num_focuses: usize,
+    /// The number of templates that are loaded for the world.
+    num_templates: usize,
+    /// How frequently are templates reloaded?
+    template_refresh: u32,
+    /// The file containing the simulation code.
+    sim_file: String,
+    /// How frequently is the simulation run?
+    sim_rate: u32,
+    /// The runtime settings given in the config file.
+    runtime_config: RuntimeValues,
+    /// The number of states that the renderer has loaded.
+    num_states: usize,
+}
+
+impl World {
+    /// Initialize a world, given the context belonging to it and its
+    /// position in `AppState::worlds`.
+    pub fn new(ctx: Ctx, index: usize) -> (lua::Backend, Self) {
+        let (frontend, backend) = lua::init(&ctx, index);
+        (backend, Self {
+            templates: RwLock::new(Templates::load(&ctx)),
+            lua: frontend,
+            sim: Sim::new(),
+            ctx,
+        })
+    }
+
+    /// Return the URL under which the admin routes for the world are found.
+    fn admin_url(&self) -> String {
+        format!("/admin/worlds/{}", self.ctx.world_name())
+    }
+
+    /// Describe the world for the admin panel.
+    pub async fn status(&self) -> TeraWorld {
+        let cfg = self.ctx.cfg();
+        let world = self.ctx.world(&cfg);
+        let sim_file = world.runtime.sim_file.read().display().to_string();
+        let mount = match (world.prefix, world.host) {
+            (Some(prefix), Some(host)) => format!("/{} and {}", prefix, host),
+            (Some(prefix), None) => format!("/{}", prefix),
+            (None, Some(host)) => host.to_string(),
+            (None, None) => String::from("/"),
+        };
+        TeraWorld {
+            name: world.name.to_string(),
+            mount,
+            admin_url: self.admin_url(),
+            num_focuses: self.lua.num_focuses(&self.ctx).await,
+            num_templates: self.num_templates(),
+            template_refresh: world.runtime.template_refresh.load(Ordering::Relaxed),
+            sim_file,
+            sim_rate: world.runtime.sim_rate.load(Ordering::Relaxed),
+            runtime_config: world.runtime.config().clone(),
+            num_states: self.lua.num_states(&self.ctx).await,
+        }
+    }
+
+    /// Describe an action performed on the world for the audit log, naming
+    /// the world unless it is the main one (e.g. `reload_focuses (fork)`).
+    pub fn audit_action(&self, action: &str) -> String {
+        if self.ctx.world_name() == MAIN_WORLD {
+            action.to_string()
+        } else {
+            format!("{} ({})", action, self.ctx.world_name())
+        }
+    }
+
+    /// Replace the world's `Tera` instance with a new one based on the
+    /// current version of the template files.
+    pub fn reload_templates(&self) {
+        *self.templates.write() = Templates::load(&self.ctx);
+    }
+
+    /// Return the number of templates that are currently loaded for the world.
+    pub fn num_templates(&self) -> usize {
+        self.templates.read().count()
+    }
+}
+
+impl super::AppState {
+    /// Return the main world.
+    pub(super) fn main_world(&self) -> &World {
+        &self.worlds[0]
+    }
+
+    /// Return the world with the given name, if there is one.
+    pub(super) fn world_named(&self, name: &str) -> Option<&World> {
+        self.worlds.iter().find(|world| world.ctx.world_name() == name)
+    }
+
+    /// Determine which world a request for a rendered page is for. A world
+    /// mounted under a prefix is selected by the first segment of the path,
+    /// which is removed; otherwise, a world mounted at the requested host
+    /// is selected, falling back to the main world.
+    pub(super) fn route_world<'a, 'p>(
+        &'a self,
+        path: &'p [&'p str],
+        headers: &HeaderMap,
+    ) -> (&'a World, &'p [&'p str]) {
+        let cfg = self.ctx.cfg();
+        if let Some((first, rest)) = path.split_first() {
+            let world = cfg.worlds.iter().find(|world| world.prefix.as_deref() == Some(*first));
+            if let Some(world) = world.and_then(|world| self.world_named(&world.name)) {
+                return (world, rest);
+            }
+        }
+        // The host header may include a port, which is ignored.
+        let host = utils::get_header(headers, hyper::header::HOST).map(utils::host_without_port);
+        let world = cfg.worlds.iter()
+            .find(|world| host.is_some() && world.host.as_deref() == host)
+            .and_then(|world| self.world_named(&world.name));
+        (world.unwrap_or_else(|| self.main_world()), path)
+    }
+
+    /// Determine which world an admin request is for. Paths like
+    /// `/admin/worlds/<name>/...` refer to a particular world, and the
+    /// rest of the path is returned with them; others refer to the main world.
+    /// Return `None` if there is no world with the given name.
+    pub(super) fn admin_world<'p>(&self, path: &'p [&'p str]) -> Option<(&World, &'p [&'p str])> {
+        match path {
+            ["worlds", name, rest @ ..] => self.world_named(name).map(|world| (world, rest)),
+            _ => Some((self.main_world(), path)),
+        }
+    }
+
+    /// Describe every world for the admin panel.
+    pub(super) async fn world_statuses(&self) -> Vec<TeraWorld> {
+        let mut statuses = Vec::new();
+        for world in &self.worlds {
+            statuses.push(world.status().await);
+        }
+        statuses
+    }
+}
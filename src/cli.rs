@@ -0,0 +1,258 @@
+//! Parses the command line into a subcommand and its arguments.
+
+use std::path::PathBuf;
+
+/// Describes how the program is invoked.
+pub const USAGE: &str = "\
+usage: nokevair [serve] <config>
+       nokevair check <config>
+       nokevair sim-once [--world <name>] <config>
+       nokevair render [--world <name>] <config> <version> <focus> [<param>]
+       nokevair state show [--world <name>] <config> <version>
+       nokevair state convert [--world <name>] <config> <version> [<json file>]
+       nokevair state diff [--world <name>] <config> <version> [<other version>]
+
+commands:
+    serve           run the server (the default)
+    check           validate the deployment, exiting with an error if there are problems
+    sim-once        run one simulation tick and write the next state file
+    render          print the HTML generated by a focus for a version of the state
+    state show      print a state file as JSON
+    state convert   write JSON (read from a file or stdin) to a new state file
+    state diff      list the differences between a state and the previous (or another) one
+
+options:
+    --world <name>  use a world listed in `[[worlds]]` instead of the main one";
+
+/// The arguments given on the command line.
+pub struct Args {
+    /// The path to the config file.
+    pub config: PathBuf,
+    /// What to do with that config.
+    pub command: Command,
+    /// The world to use instead of the main one, if any.
+    pub world: Option<String>,
+}
+
+/// Represents a subcommand.
+pub enum Command {
+    /// Run the server.
+    Serve,
+    /// Validate the deployment without serving it.
+    Check,
+    /// Run one simulation tick.
+    SimOnce,
+    /// Render a page without serving it.
+    Render {
+        /// The version of the state to use.
+        ver: u32,
+        /// The focus to render (e.g. `people`).
+        focus: String,
+        /// The value that would be passed in the `i` query parameter.
+        param: Option<String>,
+    },
+    /// Print a state file as JSON.
+    StateShow {
+        /// The version of the state to print.
+        ver: u32,
+    },
+    /// Write JSON to a new state file.
+    StateConvert {
+        /// The version of the state to create.
+        ver: u32,
+        /// The JSON file to read, or `None` for stdin.
+        input: Option<PathBuf>,
+    },
+    /// Compare two state files.
+    StateDiff {
+        /// The newer version of the state.
+        ver: u32,
+        /// The version to compare against, or `None` for the previous one.
+        other: Option<u32>,
+    },
+}
+
+/// Parse the command-line arguments (excluding the program name). Return `Ok(None)`
+/// if help was requested, and a description of the problem if they are invalid.
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Option<Args>, String> {
+    let mut args = args.into_iter().collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        return Ok(None);
+    }
+    let world = match args.iter().position(|arg| arg == "--world") {
+        Some(idx) if idx + 1 < args.len() => {
+            let name = args.remove(idx + 1);
+            args.remove(idx);
+            Some(name)
+        }
+        Some(_) => return Err(String::from("no world specified")),
+        None => None,
+    };
+    // `--check <config>` predates the `check` subcommand.
+    if let Some(idx) = args.iter().position(|arg| arg == "--check") {
+        args[idx] = String::from("check");
+        args[..=idx].rotate_right(1);
+    }
+
+    let mut args = args.into_iter().peekable();
+    let name = match args.peek().map(String::as_str) {
+        Some("serve") | Some("check") | Some("sim-once") | Some("render") =>
+            args.next().unwrap_or_default(),
+        Some("state") => {
+            args.next();
+            match args.next().as_deref() {
+                Some("show") => String::from("state show"),
+                Some("convert") => String::from("state convert"),
+                Some("diff") => String::from("state diff"),
+                Some(other) => return Err(format!("unknown state command '{}'", other)),
+                None => return Err(String::from("no state command specified")),
+            }
+        }
+        // The config file may be given on its own to run the server.
+        _ => String::from("serve"),
+    };
+
+    let config = PathBuf::from(args.next().ok_or("no config file specified")?);
+
+    /// Parse a version of the state.
+    fn version(arg: Option<String>) -> Result<u32, String> {
+        let arg = arg.ok_or("no version specified")?;
+        arg.parse().map_err(|_| format!("invalid version '{}'", arg))
+    }
+
+    let command = match name.as_str() {
+        "serve" => Command::Serve,
+        "check" => Command::Check,
+        "sim-once" => Command::SimOnce,
+        "render" => Command::Render {
+            ver: version(args.next())?,
+            focus: args.next().ok_or("no focus specified")?,
+            param: args.next(),
+        },
+        "state show" => Command::StateShow { ver: version(args.next())? },
+        "state convert" => Command::StateConvert {
+            ver: version(args.next())?,
+            input: args.next().map(PathBuf::from),
+        },
+        "state diff" => Command::StateDiff {
+            ver: version(args.next())?,
+            other: args.next().map(|arg| version(Some(arg))).transpose()?,
+        },
+        _ => unreachable!("every command name is handled above"),
+    };
+
+    if world.is_some() && matches!(command, Command::Serve | Command::Check) {
+        return Err(format!("'{}' applies to every world, so '--world' can't be used", name));
+    }
+
+    match args.next() {
+        Some(arg) => Err(format!("unexpected argument '{}'", arg)),
+        None => Ok(Some(Args { config, command, world })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a command line given as a string of space-separated arguments.
+    fn parse_str(args: &str) -> Result<Option<Args>, String> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    /// Parse a command line that is expected to be valid.
+    fn parse_ok(args: &str) -> Args {
+        parse_str(args).unwrap().unwrap()
+    }
+
+    /// Return the error produced by parsing an invalid command line.
+    fn parse_err(args: &str) -> String {
+        match parse_str(args) {
+            Err(e) => e,
+            Ok(_) => panic!("'{}' should be invalid", args),
+        }
+    }
+
+    #[test]
+    fn serve() {
+        for line in &["Config.toml", "serve Config.toml"] {
+            let args = parse_ok(line);
+            assert_eq!(args.config, PathBuf::from("Config.toml"));
+            assert!(matches!(args.command, Command::Serve));
+            assert_eq!(args.world, None);
+        }
+    }
+
+    #[test]
+    fn check() {
+        assert!(matches!(parse_ok("check Config.toml").command, Command::Check));
+        let args = parse_ok("--check Config.toml");
+        assert!(matches!(args.command, Command::Check));
+        assert_eq!(args.config, PathBuf::from("Config.toml"));
+    }
+
+    #[test]
+    fn sim_once() {
+        let args = parse_ok("sim-once --world fork Config.toml");
+        assert!(matches!(args.command, Command::SimOnce));
+        assert_eq!(args.world.as_deref(), Some("fork"));
+    }
+
+    #[test]
+    fn render() {
+        match parse_ok("render Config.toml 5 people").command {
+            Command::Render { ver: 5, focus, param: None } => assert_eq!(focus, "people"),
+            _ => panic!("expected a render command without a parameter"),
+        }
+        match parse_ok("render Config.toml 5 people 3").command {
+            Command::Render { param, .. } => assert_eq!(param.as_deref(), Some("3")),
+            _ => panic!("expected a render command"),
+        }
+    }
+
+    #[test]
+    fn state_commands() {
+        assert!(matches!(parse_ok("state show Config.toml 2").command,
+            Command::StateShow { ver: 2 }));
+        match parse_ok("state convert Config.toml 2 state.json").command {
+            Command::StateConvert { ver: 2, input } => {
+                assert_eq!(input, Some(PathBuf::from("state.json")))
+            }
+            _ => panic!("expected a state convert command"),
+        }
+        assert!(matches!(parse_ok("state convert Config.toml 2").command,
+            Command::StateConvert { ver: 2, input: None }));
+        assert!(matches!(parse_ok("state diff Config.toml 3").command,
+            Command::StateDiff { ver: 3, other: None }));
+        assert!(matches!(parse_ok("state diff Config.toml 3 1").command,
+            Command::StateDiff { ver: 3, other: Some(1) }));
+    }
+
+    #[test]
+    fn help() {
+        assert!(parse_str("--help").unwrap().is_none());
+        assert!(parse_str("render Config.toml -h").unwrap().is_none());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_err(""), "no config file specified");
+        assert_eq!(parse_err("check"), "no config file specified");
+        assert_eq!(parse_err("sim-once Config.toml --world"), "no world specified");
+        assert_eq!(parse_err("render Config.toml"), "no version specified");
+        assert_eq!(parse_err("render Config.toml x people"), "invalid version 'x'");
+        assert_eq!(parse_err("render Config.toml 5"), "no focus specified");
+        assert_eq!(parse_err("state"), "no state command specified");
+        assert_eq!(parse_err("state list Config.toml"), "unknown state command 'list'");
+        assert_eq!(parse_err("state diff Config.toml 3 y"), "invalid version 'y'");
+        assert_eq!(parse_err("check Config.toml extra"), "unexpected argument 'extra'");
+        assert_eq!(
+            parse_err("check --world fork Config.toml"),
+            "'check' applies to every world, so '--world' can't be used",
+        );
+        assert_eq!(
+            parse_err("--world fork Config.toml"),
+            "'serve' applies to every world, so '--world' can't be used",
+        );
+    }
+}
@@ -38,7 +38,7 @@ fn as_ident<'a>(v: &'a LV) -> Option<&'a str> {
         LV::String(s) => s.as_bytes(),
         _ => return None,
     };
-    if !is_ident_start(*bs.get(0)?) {
+    if !is_ident_start(*bs.first()?) {
         return None;
     }
     if !bs.iter().skip(1).all(|&b| b.is_ascii_digit() || is_ident_start(b)) {
@@ -192,10 +192,8 @@ pub fn lua_to_json(lua: LV) -> rlua::Result<TV> {
                 Ok(vals.into())
             } else {
                 let mut string_keys = serde_json::Map::new();
-                for pair in t.pairs::<String, LV>() {
-                    if let Ok((k, v)) = pair {
-                        string_keys.insert(k, lua_to_json(v)?);
-                    }
+                for (k, v) in t.pairs::<String, LV>().flatten() {
+                    string_keys.insert(k, lua_to_json(v)?);
                 }
                 Ok(string_keys.into())
             }
@@ -283,3 +281,180 @@ pub fn lua_to_string(lua: LV) -> String {
     fmt(lua, &mut result);
     result
 }
+
+/// The key of the JSON object that holds a map whose keys aren't all strings
+/// (e.g. a sparse Lua table like `{[5] = true}`), as a list of `[key, value]`
+/// pairs: `{"$pairs": [[5, true]]}`.
+const PAIRS_KEY: &str = "$pairs";
+
+/// Create a JSON value from a MessagePack value, so that states can be
+/// inspected and edited by hand. Maps with keys that aren't strings are
+/// written as in `PAIRS_KEY`, so that `json_to_msgpack` restores them exactly.
+pub fn msgpack_to_json(mp: MPV) -> io::Result<serde_json::Value> {
+    /// Interpret a MessagePack string or binary value as text,
+    /// or return `None` if it is some other kind of value.
+    fn to_text(mp: &MPV) -> Option<io::Result<String>> {
+        let bytes = match mp {
+            MPV::String(s) => s.as_bytes().to_vec(),
+            MPV::Binary(b) => b.clone(),
+            _ => return None,
+        };
+        Some(String::from_utf8(bytes).map_err(|_| invalid("strings must be valid UTF-8")))
+    }
+
+    /// Convert a float, which JSON can only represent if it is finite.
+    fn float(x: f64) -> io::Result<serde_json::Value> {
+        serde_json::Number::from_f64(x)
+            .map(serde_json::Value::Number)
+            .ok_or_else(|| invalid("infinite and NaN numbers cannot be converted"))
+    }
+
+    match mp {
+        MPV::Nil => Ok(serde_json::Value::Null),
+
+        MPV::Boolean(b) => Ok(b.into()),
+
+        MPV::Integer(i) => i.as_i64().map(Into::into)
+            .or_else(|| i.as_u64().map(Into::into))
+            .ok_or_else(|| invalid("int is out of range")),
+
+        MPV::F32(x) => float(x.into()),
+
+        MPV::F64(x) => float(x),
+
+        s @ MPV::String(_) | s @ MPV::Binary(_) => to_text(&s).unwrap().map(Into::into),
+
+        MPV::Array(a) => a.into_iter().map(msgpack_to_json).collect::<io::Result<Vec<_>>>()
+            .map(Into::into),
+
+        MPV::Map(m) => {
+            // A string-keyed map is written as an object, unless it could be
+            // mistaken for one written as a list of pairs.
+            let is_pairs_key = |k: &MPV| {
+                to_text(k).and_then(Result::ok).as_deref() == Some(PAIRS_KEY)
+            };
+            let is_plain = m.iter().all(|(k, _)| to_text(k).is_some())
+                && !(m.len() == 1 && is_pairs_key(&m[0].0));
+            let mut map = serde_json::Map::new();
+            if is_plain {
+                for (k, v) in m {
+                    map.insert(to_text(&k).unwrap()?, msgpack_to_json(v)?);
+                }
+            } else {
+                let pairs = m.into_iter()
+                    .map(|(k, v)| Ok(vec![msgpack_to_json(k)?, msgpack_to_json(v)?].into()))
+                    .collect::<io::Result<Vec<serde_json::Value>>>()?;
+                map.insert(String::from(PAIRS_KEY), pairs.into());
+            }
+            Ok(map.into())
+        }
+
+        MPV::Ext(_, _) => Err(invalid("extension data cannot be converted")),
+    }
+}
+
+/// Create a MessagePack value from a JSON value written by `msgpack_to_json`
+/// (or by hand). Strings are stored as binary data, like the strings in states
+/// written by the simulation. Fail if a list of pairs is malformed.
+pub fn json_to_msgpack(json: serde_json::Value) -> io::Result<MPV> {
+    use serde_json::Value as JV;
+    Ok(match json {
+        JV::Null => MPV::Nil,
+
+        JV::Bool(b) => b.into(),
+
+        JV::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), _, _) => i.into(),
+            (None, Some(u), _) => u.into(),
+            (None, None, Some(x)) => x.into(),
+            (None, None, None) => return Err(invalid("number is out of range")),
+        },
+
+        JV::String(s) => s.as_bytes().into(),
+
+        JV::Array(a) => a.into_iter().map(json_to_msgpack).collect::<io::Result<Vec<_>>>()?
+            .into(),
+
+        JV::Object(mut o) if o.len() == 1 && o.contains_key(PAIRS_KEY) => {
+            let pairs = match o.remove(PAIRS_KEY) {
+                Some(JV::Array(pairs)) => pairs,
+                _ => return Err(invalid("\"$pairs\" must be a list of [key, value] pairs")),
+            };
+            pairs.into_iter()
+                .map(|pair| match pair {
+                    JV::Array(pair) if pair.len() == 2 => {
+                        let mut pair = pair.into_iter().map(json_to_msgpack);
+                        Ok((pair.next().unwrap()?, pair.next().unwrap()?))
+                    }
+                    _ => Err(invalid("\"$pairs\" must be a list of [key, value] pairs")),
+                })
+                .collect::<io::Result<Vec<_>>>()?
+                .into()
+        }
+
+        JV::Object(o) => o.into_iter()
+            .map(|(k, v)| Ok((k.as_bytes().into(), json_to_msgpack(v)?)))
+            .collect::<io::Result<Vec<_>>>()?
+            .into(),
+    })
+}
+
+/// Create an error describing why a value cannot be converted.
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Convert a MessagePack value to JSON text and back, as `state show`
+    /// followed by `state convert` does.
+    fn round_trip(mp: &MPV) -> MPV {
+        let json = msgpack_to_json(mp.clone()).unwrap();
+        let text = serde_json::to_string(&json).unwrap();
+        json_to_msgpack(serde_json::from_str(&text).unwrap()).unwrap()
+    }
+
+    /// A binary string, as written by the simulation.
+    fn bin(s: &str) -> MPV {
+        s.as_bytes().into()
+    }
+
+    #[test]
+    fn string_keyed_maps_become_objects() {
+        let mp = MPV::Map(vec![(bin("people"), MPV::Array(vec![1.into(), 2.5.into()]))]);
+        let json = msgpack_to_json(mp.clone()).unwrap();
+        assert_eq!(json, serde_json::json!({ "people": [1, 2.5] }));
+        assert_eq!(round_trip(&mp), mp);
+    }
+
+    #[test]
+    fn other_keys_survive_a_round_trip() {
+        let mp = MPV::Map(vec![
+            (5.into(), bin("five")),
+            (bin("name"), true.into()),
+            (1.5.into(), MPV::Nil),
+        ]);
+        assert_eq!(round_trip(&mp), mp);
+    }
+
+    #[test]
+    fn pairs_key_is_not_ambiguous() {
+        let mp = MPV::Map(vec![(bin(PAIRS_KEY), MPV::Array(vec![]))]);
+        assert_eq!(round_trip(&mp), mp);
+    }
+
+    #[test]
+    fn large_integers_survive_a_round_trip() {
+        let mp = MPV::Array(vec![u64::MAX.into(), i64::MIN.into(), 1.0.into()]);
+        assert_eq!(round_trip(&mp), mp);
+    }
+
+    #[test]
+    fn unconvertible_values_are_rejected() {
+        assert!(msgpack_to_json(f64::NAN.into()).is_err());
+        let json = serde_json::json!({ "$pairs": [[1]] });
+        assert!(json_to_msgpack(json).is_err());
+    }
+}
@@ -3,41 +3,115 @@
 
 use async_trait::async_trait;
 
-use hyper::server::conn::AddrStream;
-use hyper::service::{make_service_fn, service_fn};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
 use hyper::{Request, Response, Body};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::time::{Duration, delay_for, timeout};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
 
+use std::io;
 use std::net::SocketAddr;
 
 use std::sync::Arc;
 
+/// Identifies which of the server's listeners a request was received by.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Listener {
+    /// The listener bound to the main address.
+    Main,
+    /// The listener bound to the separate admin address, if there is one.
+    Admin,
+    /// The listener that redirects plain HTTP requests to HTTPS, if there is one.
+    Redirect,
+}
+
 /// Represents a type capable of being used to generate responses to a request
 /// (i.e. server state).
 #[async_trait]
 pub trait Respond: Send + Sync + 'static {
     /// Generate a response to the request.
-    async fn respond(&self, addr: SocketAddr, req: Request<Body>) -> Response<Body>;
-    /// Respond to the server shutting down due to a Hyper error.
-    fn shutdown_on_err(&self, err: hyper::Error);
+    async fn respond(
+        &self,
+        addr: SocketAddr,
+        listener: Listener,
+        req: Request<Body>,
+    ) -> Response<Body>;
+    /// Return the TLS configuration that new connections to `listener` are
+    /// accepted with, or `None` if they use plain HTTP.
+    fn tls_config(&self, listener: Listener) -> Option<Arc<ServerConfig>>;
+    /// Respond to a connection that could not be accepted (or whose TLS
+    /// handshake failed, in which case the address of the client is known).
+    fn connection_failed(&self, addr: Option<SocketAddr>, err: io::Error);
+}
+
+/// How long (in seconds) a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Bind a listener to the given address, so that it can be passed to `run_server`.
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    // This is how `hyper::Server::bind` creates its listener.
+    TcpListener::from_std(std::net::TcpListener::bind(addr)?)
 }
 
-/// Run a server, using `responder` to generate responses to requests. Keep running
-/// until Hyper experiences an error.
-pub async fn run_server<R: Respond>(responder: &Arc<R>, addr: SocketAddr) {
-    let service = make_service_fn(move |addr_stream: &AddrStream| {
-        let remote_addr = addr_stream.remote_addr();
+/// Run a server, using `responder` to generate responses to requests received
+/// by `listener` through `tcp`. Keep running until the program exits.
+pub async fn run_server<R: Respond>(responder: &Arc<R>, mut tcp: TcpListener, listener: Listener) {
+    loop {
+        let (stream, remote_addr) = match tcp.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Errors like running out of file descriptors are usually temporary.
+                responder.connection_failed(None, e);
+                delay_for(Duration::from_secs(1)).await;
+                continue
+            }
+        };
         let responder = Arc::clone(responder);
-        async move {
-            hyper::Result::Ok(service_fn(move |req| {
-                let responder = Arc::clone(&responder);
-                async move {
-                    let response = responder.respond(remote_addr, req);
-                    hyper::Result::Ok(response.await)
+        tokio::spawn(async move {
+            // The config is looked up for each connection so that a new
+            // certificate takes effect without restarting the listener.
+            match responder.tls_config(listener) {
+                Some(config) => {
+                    let handshake = TlsAcceptor::from(config).accept(stream);
+                    match timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), handshake).await {
+                        Ok(Ok(stream)) => {
+                            serve_connection(responder, stream, remote_addr, listener).await
+                        }
+                        Ok(Err(e)) => responder.connection_failed(Some(remote_addr), e),
+                        Err(_) => responder.connection_failed(Some(remote_addr), io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "TLS handshake timed out",
+                        )),
+                    }
                 }
-            }))
+                None => serve_connection(responder, stream, remote_addr, listener).await,
+            }
+        });
+    }
+}
+
+/// Serve the requests received over a single connection.
+async fn serve_connection<R, S>(
+    responder: Arc<R>,
+    stream: S,
+    remote_addr: SocketAddr,
+    listener: Listener,
+)
+where
+    R: Respond,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| {
+        let responder = Arc::clone(&responder);
+        async move {
+            let response = responder.respond(remote_addr, listener, req);
+            hyper::Result::Ok(response.await)
         }
     });
-    if let Err(e) = hyper::Server::bind(&addr).serve(service).await {
-        responder.shutdown_on_err(e);
-    }
+    // Errors on a single connection (like the client going away) only affect
+    // that client, and aren't worth reporting.
+    let _ = Http::new().serve_connection(stream, service).await;
 }
@@ -1,5 +1,9 @@
 //! The server (and driver program) for Nokevair.
 
+use futures::future::join_all;
+
+use std::env;
+use std::process;
 use std::sync::Arc;
 
 #[macro_use]
@@ -7,24 +11,100 @@ mod utils;
 
 mod app;
 use app::AppState;
-use app::Ctx;
+use app::{Ctx, Users};
+
+mod cli;
+use cli::Command;
 
 mod conv;
 mod hyper_boilerplate;
+use hyper_boilerplate::Listener;
 
 #[tokio::main]
 async fn main() {
-    let ctx = match Ctx::load() {
+    let args = match cli::parse(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", cli::USAGE);
+            return
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            process::exit(2)
+        }
+    };
+
+    // Only the server writes to the log file, so that offline commands
+    // can be run alongside it.
+    let is_serve = matches!(args.command, Command::Serve);
+    let mut ctx = match Ctx::load(&args.config, is_serve) {
         Some(c) => c,
-        None => return,
+        None => process::exit(1),
+    };
+    if let Some(world) = &args.world {
+        if ctx.cfg().world(world).is_none() {
+            ctx.log.err(format_args!("there is no world named '{}'", world));
+            process::exit(1);
+        }
+        ctx = ctx.for_world(world);
+    }
+
+    let succeeded = match args.command {
+        Command::Serve => {
+            serve(ctx).await;
+            true
+        }
+        Command::Check => app::check(&ctx),
+        Command::SimOnce => app::sim_once(&ctx),
+        Command::Render { ver, focus, param } => app::render(ctx, ver, focus, param).await,
+        Command::StateShow { ver } => app::state_show(&ctx, ver),
+        Command::StateConvert { ver, input } => app::state_convert(&ctx, ver, input.as_deref()),
+        Command::StateDiff { ver, other } => app::state_diff(&ctx, ver, other),
+    };
+    if !succeeded {
+        process::exit(1);
+    }
+}
+
+/// Run the server until it shuts down.
+async fn serve(ctx: Ctx) {
+    let cfg = ctx.cfg();
+    let addrs = [
+        (Some(cfg.addr), Listener::Main),
+        (cfg.network.admin_addr, Listener::Admin),
+        (cfg.tls.as_ref().and_then(|tls| tls.redirect_addr), Listener::Redirect),
+    ];
+
+    // Bind every listener before starting, so that the server
+    // exits if any of its addresses are unavailable.
+    let mut listeners = Vec::new();
+    for (addr, listener) in addrs.iter() {
+        if let Some(addr) = addr {
+            match hyper_boilerplate::bind(*addr) {
+                Ok(tcp) => listeners.push((tcp, *listener)),
+                Err(e) => {
+                    ctx.log.err(format_args!("could not listen on {}: {}", addr, e));
+                    process::exit(1)
+                }
+            }
+        }
+    }
+
+    let users = match Users::load(&ctx.log, &ctx.cfg()) {
+        Some(users) => users,
+        None => process::exit(1),
     };
-    let addr = ctx.cfg.addr;
-    let (mut lua_backend, app_state) = AppState::new(ctx);
+    let (mut lua_backends, app_state) = AppState::new(ctx, users);
+    if !app_state.load_tls() {
+        process::exit(1)
+    }
     let app_state = Arc::new(app_state);
     
     tokio::join!(
         app_state.do_scheduled(),
-        lua_backend.run(&app_state),
-        hyper_boilerplate::run_server(&app_state, addr),
+        app::run_backends(&mut lua_backends, &app_state),
+        join_all(listeners.into_iter().map(|(tcp, listener)| {
+            hyper_boilerplate::run_server(&app_state, tcp, listener)
+        })),
     );
 }
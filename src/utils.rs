@@ -16,11 +16,76 @@ pub fn remove_suffix<'a>(s: &'a str, suffix: &str) -> Option<&'a str> {
 
 /// Hash the input string with SHA256.
 pub fn sha256(s: &str) -> String {
+    sha256_bytes(s.as_bytes())
+}
+
+/// Hash the input bytes with SHA256.
+pub fn sha256_bytes(bytes: &[u8]) -> String {
     use sha2::{Sha256, digest::Digest};
     let mut hasher = Sha256::default();
-    hasher.input(s);
+    hasher.input(bytes);
     let result: &[u8] = &hasher.result();
-    hex::encode(&result)
+    hex::encode(result)
+}
+
+/// Compute the HMAC-SHA256 of the input string using the given key.
+pub fn hmac_sha256(key: &[u8], s: &str) -> String {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    let mut mac = Hmac::<Sha256>::new_varkey(key)
+        .expect("HMAC accepts keys of any length");
+    mac.input(s.as_bytes());
+    hex::encode(mac.result().code())
+}
+
+/// Compare two strings in time that depends only on their lengths,
+/// so that secrets cannot be recovered by timing the comparison.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes()
+        .zip(b.bytes())
+        .fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Return the value of a header sent with a request, if it is present and valid UTF-8.
+pub fn get_header<K: hyper::header::AsHeaderName>(headers: &hyper::HeaderMap, name: K) -> Option<&str> {
+    headers.get(name).and_then(|h| h.to_str().ok())
+}
+
+/// Return the value of a cookie sent with a request, if it is present.
+pub fn get_cookie<'a>(headers: &'a hyper::HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(hyper::header::COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, '=');
+            Some((parts.next()?, parts.next()?))
+        })
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v)
+}
+
+/// Percent-encode every byte of a string except the unreserved characters of
+/// RFC 3986, so that it can be used as any part of a URI.
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Return the host named by a `Host` header, without the port if one is given.
+pub fn host_without_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => host,
+    }
 }
 
 /// Convert the body of a request into a byte vector.
@@ -1,15 +1,24 @@
+const usernameBox = document.getElementById("username-box");
 const passwordBox = document.getElementById("password-box");
+const codeBox = document.getElementById("code-box");
 
 function clearError() {
     passwordBox.classList.remove("wrong");
 }
 
 async function submit() {
-    let msg = token + ":" + passwordBox.value;
-    let hash = forge_sha256(msg);
+    let user = usernameBox.value;
+    let saltResponse = await fetch("/login/salt?i=" + encodeURIComponent(user));
+    if (!saltResponse.ok) {
+        passwordBox.classList.add("wrong");
+        return;
+    }
+    let salt = await saltResponse.text();
+    let secret = forge_sha256(salt + ":" + passwordBox.value);
+    let hash = forge_sha256(token + ":" + secret);
     let response = await fetch("/login", {
         method: "POST",
-        body: JSON.stringify({ token, hash }),
+        body: JSON.stringify({ user, token, hash, code: codeBox.value }),
     });
     if (response.redirected) {
         window.location.assign(response.url);